@@ -0,0 +1,85 @@
+//! Fuzz target feeding arbitrary datagrams to uTP sockets, for use with a
+//! file-based fuzzer such as AFL:
+//!
+//!     afl-fuzz -i seeds -o findings target/debug/utp-fuzz
+//!
+//! The input is a sequence of datagrams, each preceded by its length as a
+//! big-endian 16-bit number. Every datagram is sent both to a socket waiting
+//! for a connection and to an established connection, with its connection id
+//! rewritten so that it reaches the connection's state machine. Any panic is
+//! a bug.
+
+extern crate utp;
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::net::udp::UdpSocket;
+
+macro_rules! iotry {
+    ($e:expr) => (match $e { Ok(v) => v, Err(e) => panic!("{}", e), })
+}
+
+fn loopback() -> SocketAddr {
+    SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }
+}
+
+/// Split the input into the datagrams it encodes, dropping a truncated last
+/// one.
+fn datagrams(input: &[u8]) -> Vec<&[u8]> {
+    let mut datagrams = Vec::new();
+    let mut rest = input;
+    while rest.len() >= 2 {
+        let len = (rest[0] as usize) << 8 | rest[1] as usize;
+        if rest.len() < 2 + len {
+            break;
+        }
+        datagrams.push(&rest[2..2 + len]);
+        rest = &rest[2 + len..];
+    }
+    datagrams
+}
+
+fn main() {
+    use utp::UtpSocket;
+    use std::io::{stdin, Read};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    let mut input = Vec::new();
+    iotry!(stdin().read_to_end(&mut input));
+
+    let mut listening = iotry!(UtpSocket::bind(loopback()));
+    let listening_addr = iotry!(listening.local_addr());
+    let client = iotry!(UtpSocket::bind(loopback()));
+    let client_addr = iotry!(client.local_addr());
+    let mut peer = iotry!(UdpSocket::bind(loopback()));
+    let peer_addr = iotry!(peer.socket_name());
+
+    // Play the remote end of the handshake by hand, accepting the SYN with a
+    // bare acknowledgement
+    let mut responder = peer.clone();
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut buf = [0; 1500];
+        let (read, _) = iotry!(responder.recv_from(&mut buf));
+        assert!(read >= 20);
+        let (connection_id, seq_nr) = (&buf[2..4], &buf[16..18]);
+        let reply = [0x21, 0, connection_id[0], connection_id[1], 0, 0, 0, 0, 0, 0, 0, 0,
+                     0, 1, 0, 0, 0, 1, seq_nr[0], seq_nr[1]];
+        iotry!(responder.send_to(&reply, client_addr));
+        tx.send([connection_id[0], connection_id[1]]).unwrap();
+    });
+    let mut connected = iotry!(client.connect(peer_addr));
+    let connection_id = rx.recv().unwrap();
+
+    for datagram in datagrams(&input[..]).into_iter() {
+        let _ = peer.send_to(datagram, listening_addr);
+        let _ = listening.process_incoming();
+
+        let mut datagram = datagram.to_vec();
+        if datagram.len() >= 4 {
+            datagram[2] = connection_id[0];
+            datagram[3] = connection_id[1];
+        }
+        let _ = peer.send_to(&datagram[..], client_addr);
+        let _ = connected.process_incoming();
+    }
+}
@@ -0,0 +1,243 @@
+//! Compression of data payloads, using the LZ4 block format.
+//!
+//! Once both peers agreed on compression during the handshake, every data
+//! payload is sent as a chunk: a flag byte telling whether the rest of the
+//! payload is compressed, followed by the data. Chunks that don't shrink are
+//! sent uncompressed.
+
+use std::cmp::min;
+use std::iter::repeat;
+
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+const MIN_MATCH: usize = 4;
+const LAST_LITERALS: usize = 5; // a block always ends with literals
+const MATCH_LIMIT: usize = 12; // no match may start past this distance from the end
+const MAX_OFFSET: usize = 65535;
+const HASH_LOG: usize = 12;
+
+/// Encode `data` as a chunk, compressing it if `compress` is set and that
+/// makes it smaller.
+pub fn encode_chunk(data: &[u8], compress: bool) -> Vec<u8> {
+    if compress {
+        let compressed = compress_block(data);
+        if compressed.len() < data.len() {
+            let mut chunk = vec!(LZ4);
+            chunk.push_all(&compressed[..]);
+            return chunk;
+        }
+    }
+
+    let mut chunk = vec!(RAW);
+    chunk.push_all(data);
+    chunk
+}
+
+/// Decode a chunk, returning `None` if it is malformed or its data is longer
+/// than `max_len` bytes.
+pub fn decode_chunk(chunk: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    match chunk.first() {
+        None => Some(Vec::new()),
+        Some(&RAW) if chunk.len() - 1 <= max_len => Some(chunk[1..].to_vec()),
+        Some(&LZ4) => decompress_block(&chunk[1..], max_len),
+        Some(_) => None,
+    }
+}
+
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    buf[i] as u32 | (buf[i + 1] as u32) << 8 | (buf[i + 2] as u32) << 16 | (buf[i + 3] as u32) << 24
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Append the part of a length that doesn't fit in a token.
+fn push_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Append a sequence of literals, followed by a match unless it is the last
+/// sequence of the block.
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((min(literals.len(), 15) << 4 | min(match_len, 15)) as u8);
+    if literals.len() >= 15 {
+        push_length(out, literals.len() - 15);
+    }
+    out.push_all(literals);
+
+    if let Some((offset, _)) = matched {
+        out.push(offset as u8);
+        out.push((offset >> 8) as u8);
+        if match_len >= 15 {
+            push_length(out, match_len - 15);
+        }
+    }
+}
+
+/// Compress `src` into a single LZ4 block.
+fn compress_block(src: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(src.len());
+    let mut table: Vec<usize> = repeat(0).take(1 << HASH_LOG).collect();
+    let mut anchor = 0;
+    let mut i = 0;
+
+    if src.len() > MATCH_LIMIT {
+        let limit = src.len() - MATCH_LIMIT;
+        let match_end = src.len() - LAST_LITERALS;
+        while i < limit {
+            let sequence = read_u32(src, i);
+            let h = hash(sequence);
+            let candidate = table[h];
+            table[h] = i;
+
+            if candidate < i && i - candidate <= MAX_OFFSET && read_u32(src, candidate) == sequence {
+                let mut len = MIN_MATCH;
+                while i + len < match_end && src[candidate + len] == src[i + len] {
+                    len += 1;
+                }
+                push_sequence(&mut out, &src[anchor..i], Some((i - candidate, len)));
+                i += len;
+                anchor = i;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    push_sequence(&mut out, &src[anchor..], None);
+    out
+}
+
+/// Read the part of a length that didn't fit in a token.
+fn read_length(src: &[u8], i: &mut usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        if *i >= src.len() {
+            return None;
+        }
+        let byte = src[*i];
+        *i += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompress a single LZ4 block, failing if it is malformed or expands past
+/// `max_len` bytes.
+fn decompress_block(src: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(min(src.len() * 2, max_len));
+    let mut i = 0;
+
+    loop {
+        if i >= src.len() {
+            return None;
+        }
+        let token = src[i];
+        i += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            match read_length(src, &mut i) {
+                Some(len) => literals += len,
+                None => return None,
+            }
+        }
+        if i + literals > src.len() || out.len() + literals > max_len {
+            return None;
+        }
+        out.push_all(&src[i..i + literals]);
+        i += literals;
+
+        // The last sequence has no match
+        if i == src.len() {
+            return Some(out);
+        }
+
+        if i + 2 > src.len() {
+            return None;
+        }
+        let offset = src[i] as usize | (src[i + 1] as usize) << 8;
+        i += 2;
+        if offset == 0 || offset > out.len() {
+            return None;
+        }
+
+        let mut len = (token & 15) as usize;
+        if len == 15 {
+            match read_length(src, &mut i) {
+                Some(extra) => len += extra,
+                None => return None,
+            }
+        }
+        len += MIN_MATCH;
+        if out.len() + len > max_len {
+            return None;
+        }
+
+        // Matches may overlap the data they produce, so copy byte by byte
+        let start = out.len() - offset;
+        for k in 0..len {
+            let byte = out[start + k];
+            out.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_chunk, decode_chunk, compress_block, decompress_block};
+
+    #[test]
+    fn test_block_roundtrip() {
+        let text = b"It was the best of times, it was the worst of times, it was the age of \
+                     wisdom, it was the age of foolishness, it was the epoch of belief";
+        let runs: Vec<u8> = (0..3000).map(|i| (i / 300) as u8).collect();
+        let noise: Vec<u8> = (0..1000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+
+        for data in [&text[..], &runs[..], &noise[..], &[][..], &[1, 2, 3][..]].iter() {
+            let block = compress_block(*data);
+            assert_eq!(decompress_block(&block[..], data.len()), Some(data.to_vec()));
+        }
+        assert!(compress_block(&runs[..]).len() < runs.len() / 10);
+    }
+
+    #[test]
+    fn test_decompress_malformed() {
+        // Match reaching before the start of the output
+        assert_eq!(decompress_block(&[0x10, b'a', 2, 0], 100), None);
+        // Truncated literals
+        assert_eq!(decompress_block(&[0x30, b'a'], 100), None);
+        // Expanding past the limit
+        let data: Vec<u8> = (0..1000).map(|_| 7).collect();
+        assert_eq!(decompress_block(&compress_block(&data[..])[..], 999), None);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 10) as u8).collect();
+        let compressed = encode_chunk(&data[..], true);
+        assert!(compressed.len() < data.len());
+        assert_eq!(decode_chunk(&compressed[..], 1000), Some(data.clone()));
+
+        let raw = encode_chunk(&data[..], false);
+        assert_eq!(raw.len(), data.len() + 1);
+        assert_eq!(decode_chunk(&raw[..], 1000), Some(data));
+
+        // Incompressible data is sent as is
+        assert_eq!(encode_chunk(&[1, 2, 3], true), vec!(0, 1, 2, 3));
+        assert_eq!(decode_chunk(&[9, 1, 2, 3], 3), None);
+
+        // Data longer than allowed is rejected, compressed or not
+        assert_eq!(decode_chunk(&compressed[..], 999), None);
+        assert_eq!(decode_chunk(&[0, 1, 2, 3], 2), None);
+    }
+}
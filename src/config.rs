@@ -0,0 +1,353 @@
+use std::default::Default;
+use std::old_io::{IoResult, IoError, InvalidInput};
+use fec::MAX_GROUP_SIZE;
+
+const DEFAULT_TARGET_DELAY: u32 = 100_000; // 100 milliseconds
+const DEFAULT_GAIN: f64 = 1.0;
+const DEFAULT_ALLOWED_INCREASE: u32 = 1;
+const MAX_GAIN: f64 = 10.0;
+const MAX_ALLOWED_INCREASE: u32 = 16;
+const DEFAULT_RECEIVE_WINDOW: u32 = 1024 * 1024; // 1 MB
+
+/// How strictly incoming packets are validated.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ParsingMode {
+    /// Drop anything that doesn't conform to the specification, and reset
+    /// connections on packets unexpected in their current state.
+    Strict,
+    /// Tolerate quirks of old or buggy implementations, such as a wrong
+    /// version number on an otherwise valid packet, and ignore unexpected
+    /// packets.
+    Lenient,
+}
+
+/// What to do with data received out of order once it fills the receive
+/// buffer.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum OverflowPolicy {
+    /// Drop the packets that don't fit, keeping the data already buffered so
+    /// that the stream stays complete once the gaps are filled.
+    DropNewest,
+    /// Discard the oldest data stranded behind a gap, along with the missing
+    /// data before it, to make room for newer data. The next read fails to
+    /// tell the application about the loss, then reading resumes with the
+    /// newer data. Suits realtime applications favouring fresh data over
+    /// complete data.
+    DropOldest,
+}
+
+/// Tunable parameters of a uTP socket.
+///
+/// Rather than setting every field by hand, start from one of the presets
+/// (`bulk`, `balanced` or `realtime`) and adjust as needed. The default
+/// configuration is `balanced`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct UtpConfig {
+    /// Queuing delay the congestion controller aims for, in microseconds
+    /// (LEDBAT's TARGET). Lower values yield lower latency at the cost of
+    /// throughput.
+    pub target_delay: u32,
+    /// How aggressively the congestion window reacts to the distance between
+    /// the measured queuing delay and the target (LEDBAT's GAIN). Must lie in
+    /// `(0, 10]`; values above 1 make the connection more aggressive than TCP
+    /// and are only suitable for private networks.
+    pub gain: f64,
+    /// How many packets the congestion window may grow past the data in
+    /// flight (LEDBAT's ALLOWED_INCREASE). Must lie in `[1, 16]`.
+    pub allowed_increase: u32,
+    /// Whether to delay acknowledgements, answering every other data packet
+    /// instead of each one.
+    pub delayed_ack: bool,
+    /// Whether to hold the acknowledgement of a lone data packet, so that the
+    /// application's reply carries it instead of a separate packet. Suits
+    /// request/response exchanges; without a reply, the acknowledgement is
+    /// sent after the delayed acknowledgement timeout.
+    pub piggyback_acks: bool,
+    /// Whether to coalesce small writes while data is in flight (Nagle's
+    /// algorithm).
+    pub nagle: bool,
+    /// Whether to spread packet transmissions evenly over the round-trip time
+    /// instead of sending them in bursts.
+    pub pacing: bool,
+    /// Maximum number of packets in flight, regardless of their size.
+    pub max_packets_in_flight: Option<usize>,
+    /// Size of the receive buffer advertised to the remote peer, in bytes.
+    pub receive_window: u32,
+    /// Upper bound of the receive buffer when growing it automatically, in
+    /// bytes. If set, the receive window starts at `receive_window` and grows
+    /// to twice the estimated bandwidth-delay product of the path, so that
+    /// high-latency links with plenty of bandwidth aren't capped by it.
+    pub max_receive_window: Option<u32>,
+    /// How strictly incoming packets are validated.
+    pub parsing: ParsingMode,
+    /// Whether to ignore packets acknowledging data that was never sent, making
+    /// it harder for a blind attacker to inject packets into a connection.
+    pub verify_acks: bool,
+    /// Cap on the memory held by a connection's buffers, in bytes. Once it is
+    /// reached, writes wait for acknowledgements and the congestion window
+    /// stops growing.
+    pub max_memory: Option<usize>,
+    /// Whether to offer compression of data payloads to the remote peer. Only
+    /// takes effect when built with the `compression` feature, and if the
+    /// remote peer supports it too.
+    pub compression: bool,
+    /// Whether to report out-of-order data packets with selective
+    /// acknowledgements. Incoming selective acknowledgements are handled
+    /// either way; turning this off works around peers mishandling them.
+    pub send_selective_acks: bool,
+    /// Number of data packets protected by each parity packet, if any. The
+    /// remote peer can then rebuild a single lost packet per group without
+    /// waiting for its retransmission, which helps on lossy links at the cost
+    /// of one extra packet per group. Only takes effect if the remote peer
+    /// sets it too. Must lie in `[2, 32]`.
+    pub parity_group: Option<u8>,
+    /// What to do with data received out of order once it fills the receive
+    /// buffer.
+    pub overflow_policy: OverflowPolicy,
+    /// Whether to check, at the end of the connection, that the data read
+    /// matches the data written on the other end, using a digest of the whole
+    /// byte stream. Only takes effect if the remote peer sets it too.
+    pub stream_digest: bool,
+    /// Interval between keep-alives sent on an idle connection, in
+    /// milliseconds, if any. Keeps NAT mappings alive during long pauses
+    /// between transfers. Must be positive.
+    ///
+    /// Keep-alives are only sent while the socket is driven: during `recv`
+    /// and `poll`, or by a reactor (see `UtpSocket::spawn_reactor`). They
+    /// wait while data is in flight or an acknowledgement is pending, as the
+    /// packets those call for keep the mappings alive themselves.
+    pub keep_alive_interval: Option<u64>,
+    /// Time without hearing from the remote peer after which the connection
+    /// is given up on, in milliseconds, if any. Blocking calls then fail
+    /// instead of waiting forever on a peer that silently disappeared. Idle
+    /// connections need keep-alives from the remote peer, sent more often
+    /// than this, to survive. Must be positive.
+    pub idle_timeout: Option<u64>,
+}
+
+impl UtpConfig {
+    /// Configuration for bulk transfers, favouring throughput and efficiency
+    /// over latency.
+    pub fn bulk() -> UtpConfig {
+        UtpConfig {
+            target_delay: DEFAULT_TARGET_DELAY,
+            gain: DEFAULT_GAIN,
+            allowed_increase: DEFAULT_ALLOWED_INCREASE,
+            delayed_ack: true,
+            piggyback_acks: false,
+            nagle: true,
+            pacing: false,
+            max_packets_in_flight: None,
+            receive_window: DEFAULT_RECEIVE_WINDOW,
+            max_receive_window: None,
+            parsing: ParsingMode::Lenient,
+            verify_acks: false,
+            max_memory: None,
+            compression: false,
+            send_selective_acks: true,
+            parity_group: None,
+            overflow_policy: OverflowPolicy::DropNewest,
+            stream_digest: false,
+            keep_alive_interval: None,
+            idle_timeout: None,
+        }
+    }
+
+    /// General purpose configuration.
+    pub fn balanced() -> UtpConfig {
+        UtpConfig {
+            target_delay: DEFAULT_TARGET_DELAY,
+            gain: DEFAULT_GAIN,
+            allowed_increase: DEFAULT_ALLOWED_INCREASE,
+            delayed_ack: false,
+            piggyback_acks: false,
+            nagle: false,
+            pacing: false,
+            max_packets_in_flight: None,
+            receive_window: DEFAULT_RECEIVE_WINDOW,
+            max_receive_window: None,
+            parsing: ParsingMode::Lenient,
+            verify_acks: false,
+            max_memory: None,
+            compression: false,
+            send_selective_acks: true,
+            parity_group: None,
+            overflow_policy: OverflowPolicy::DropNewest,
+            stream_digest: false,
+            keep_alive_interval: None,
+            idle_timeout: None,
+        }
+    }
+
+    /// Configuration for interactive or streaming traffic, keeping queuing
+    /// delay and burstiness low at the cost of throughput: the congestion
+    /// window backs off at a quarter of the usual queuing delay, and grows
+    /// half as fast below it.
+    pub fn realtime() -> UtpConfig {
+        UtpConfig {
+            target_delay: DEFAULT_TARGET_DELAY / 4,
+            gain: DEFAULT_GAIN / 2.0,
+            allowed_increase: DEFAULT_ALLOWED_INCREASE,
+            delayed_ack: false,
+            piggyback_acks: false,
+            nagle: false,
+            pacing: true,
+            max_packets_in_flight: None,
+            receive_window: DEFAULT_RECEIVE_WINDOW,
+            max_receive_window: None,
+            parsing: ParsingMode::Lenient,
+            verify_acks: false,
+            max_memory: None,
+            compression: false,
+            send_selective_acks: true,
+            parity_group: None,
+            overflow_policy: OverflowPolicy::DropNewest,
+            stream_digest: false,
+            keep_alive_interval: None,
+            idle_timeout: None,
+        }
+    }
+
+    /// Check that every parameter lies within its safe range.
+    pub fn validate(&self) -> IoResult<()> {
+        if !(self.gain > 0.0 && self.gain <= MAX_GAIN) {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "Invalid configuration",
+                detail: Some(format!("gain must lie in (0, {}], got {}", MAX_GAIN, self.gain)),
+            });
+        }
+        if self.allowed_increase < 1 || self.allowed_increase > MAX_ALLOWED_INCREASE {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "Invalid configuration",
+                detail: Some(format!("allowed_increase must lie in [1, {}], got {}",
+                                     MAX_ALLOWED_INCREASE, self.allowed_increase)),
+            });
+        }
+        if let Some(max_receive_window) = self.max_receive_window {
+            if max_receive_window < self.receive_window {
+                return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "Invalid configuration",
+                    detail: Some(format!("max_receive_window must be at least receive_window, got {}",
+                                         max_receive_window)),
+                });
+            }
+        }
+        if self.keep_alive_interval == Some(0) {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "Invalid configuration",
+                detail: Some("keep_alive_interval must be positive".to_string()),
+            });
+        }
+        if self.idle_timeout == Some(0) {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "Invalid configuration",
+                detail: Some("idle_timeout must be positive".to_string()),
+            });
+        }
+        if let Some(group) = self.parity_group {
+            if group < 2 || group > MAX_GROUP_SIZE {
+                return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "Invalid configuration",
+                    detail: Some(format!("parity_group must lie in [2, {}], got {}",
+                                         MAX_GROUP_SIZE, group)),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for UtpConfig {
+    fn default() -> UtpConfig {
+        UtpConfig::balanced()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::UtpConfig;
+    use std::default::Default;
+
+    #[test]
+    fn test_default_is_balanced() {
+        let config: UtpConfig = Default::default();
+        assert_eq!(config, UtpConfig::balanced());
+    }
+
+    #[test]
+    fn test_presets() {
+        let bulk = UtpConfig::bulk();
+        let realtime = UtpConfig::realtime();
+
+        assert!(bulk.nagle && bulk.delayed_ack && !bulk.pacing);
+        assert!(!realtime.nagle && !realtime.delayed_ack && realtime.pacing);
+        assert!(realtime.target_delay < bulk.target_delay);
+        assert!(realtime.gain < bulk.gain);
+    }
+
+    #[test]
+    fn test_presets_congestion() {
+        use congestion::{CongestionControl, Ledbat, AckSample};
+        use socket::MSS;
+
+        let mut ledbat = Ledbat;
+        let bulk = UtpConfig::bulk();
+        let realtime = UtpConfig::realtime();
+        let cwnd = 10 * MSS;
+        let ack = AckSample {
+            bytes_acked: MSS,
+            queuing_delay: 0,
+            flight_size: cwnd,
+        };
+
+        // Without queuing, both grow the window, realtime more slowly
+        let bulk_cwnd = ledbat.on_ack(cwnd, &ack, &bulk);
+        let realtime_cwnd = ledbat.on_ack(cwnd, &ack, &realtime);
+        assert!(realtime_cwnd > cwnd);
+        assert!(realtime_cwnd < bulk_cwnd);
+
+        // A queuing delay between the two targets only slows realtime down
+        let queued = AckSample { queuing_delay: realtime.target_delay as i64 * 2, ..ack };
+        assert!(ledbat.on_ack(cwnd, &queued, &bulk) > cwnd);
+        assert!(ledbat.on_ack(cwnd, &queued, &realtime) < cwnd);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(UtpConfig::bulk().validate().is_ok());
+        assert!(UtpConfig::balanced().validate().is_ok());
+        assert!(UtpConfig::realtime().validate().is_ok());
+
+        let mut config = UtpConfig::balanced();
+        config.gain = 0.0;
+        assert!(config.validate().is_err());
+
+        let mut config = UtpConfig::balanced();
+        config.allowed_increase = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = UtpConfig::balanced();
+        config.max_receive_window = Some(config.receive_window / 2);
+        assert!(config.validate().is_err());
+
+        let mut config = UtpConfig::balanced();
+        config.parity_group = Some(1);
+        assert!(config.validate().is_err());
+        config.parity_group = Some(8);
+        assert!(config.validate().is_ok());
+
+        let mut config = UtpConfig::balanced();
+        config.keep_alive_interval = Some(0);
+        assert!(config.validate().is_err());
+
+        let mut config = UtpConfig::balanced();
+        config.idle_timeout = Some(0);
+        assert!(config.validate().is_err());
+    }
+}
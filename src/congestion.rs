@@ -0,0 +1,142 @@
+//! Congestion control: how the congestion window of a socket reacts to
+//! acknowledgements, losses and timeouts.
+//!
+//! Sockets use LEDBAT (RFC 6817) unless given another controller with
+//! `UtpSocketBuilder::congestion_control`, e.g. a fixed window for tests on a
+//! local network.
+
+use std::cmp::{min, max};
+use config::UtpConfig;
+use socket::{MSS, MIN_CWND};
+
+/// What an acknowledgement tells about the path.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct AckSample {
+    /// Length of the acknowledging packet, in bytes.
+    pub bytes_acked: u32,
+    /// Estimated queuing delay along the path, in microseconds.
+    pub queuing_delay: i64,
+    /// Bytes in flight when the acknowledgement arrived.
+    pub flight_size: u32,
+}
+
+/// Sizes the congestion window, in bytes, which bounds the data a socket
+/// keeps in flight.
+pub trait CongestionControl {
+    /// Return the congestion window following an acknowledgement.
+    fn on_ack(&mut self, cwnd: u32, ack: &AckSample, config: &UtpConfig) -> u32;
+
+    /// Return the congestion window following the loss of a packet.
+    fn on_loss(&mut self, cwnd: u32) -> u32 {
+        max(cwnd / 2, MIN_CWND * MSS)
+    }
+
+    /// Return the congestion window after data in flight went unacknowledged
+    /// until the retransmission timeout.
+    fn on_timeout(&mut self, _cwnd: u32) -> u32 {
+        MSS
+    }
+}
+
+/// Low Extra Delay Background Transport: keeps the queuing delay the
+/// connection causes close to `UtpConfig::target_delay`, growing the window
+/// by `UtpConfig::gain` and at most `UtpConfig::allowed_increase` packets
+/// past the data in flight.
+#[derive(Clone,Copy,Debug)]
+pub struct Ledbat;
+
+impl CongestionControl for Ledbat {
+    fn on_ack(&mut self, cwnd: u32, ack: &AckSample, config: &UtpConfig) -> u32 {
+        use std::num::Int;
+
+        let target = config.target_delay as f64;
+        let off_target = (target - ack.queuing_delay as f64) / target;
+
+        let increase = config.gain * off_target * ack.bytes_acked as f64 * MSS as f64 / cwnd as f64;
+        let max_allowed_cwnd = ack.flight_size + config.allowed_increase * MSS;
+        debug!("max_allowed_cwnd: {}", max_allowed_cwnd);
+
+        // A queuing delay above the target calls for a decrease
+        if increase < 0.0 {
+            let cwnd = max(min(cwnd.saturating_sub((-increase) as u32), max_allowed_cwnd),
+                           MIN_CWND * MSS);
+            debug!("cwnd: {}", cwnd);
+            return cwnd;
+        }
+
+        match cwnd.checked_add(increase as u32) {
+            Some(grown) => {
+                let cwnd = max(min(grown, max_allowed_cwnd), MIN_CWND * MSS);
+                debug!("cwnd: {}", cwnd);
+                cwnd
+            }
+            None => {
+                // FIXME: This shouldn't happen at all, more investigation is needed to ascertain the
+                // true cause of the miscalculation of the congestion window increase. For now, we
+                // simply ignore meaningly large increases.
+                cwnd
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::default::Default;
+    use super::{CongestionControl, Ledbat, AckSample};
+    use config::UtpConfig;
+    use socket::{MSS, MIN_CWND};
+
+    #[test]
+    fn test_ledbat_bounds() {
+        let mut ledbat = Ledbat;
+        let config: UtpConfig = Default::default();
+        let ack = AckSample {
+            bytes_acked: MSS,
+            queuing_delay: 0,
+            flight_size: 2 * MSS,
+        };
+
+        // The window can't outgrow the data in flight by more than allowed
+        let cwnd = ledbat.on_ack(10 * MSS, &ack, &config);
+        assert_eq!(cwnd, (2 + config.allowed_increase) * MSS);
+
+        // Nor shrink below the minimum
+        let idle = AckSample { flight_size: 0, ..ack };
+        assert_eq!(ledbat.on_ack(cwnd, &idle, &config), MIN_CWND * MSS);
+
+        assert_eq!(ledbat.on_loss(8 * MSS), 4 * MSS);
+        assert_eq!(ledbat.on_loss(MIN_CWND * MSS), MIN_CWND * MSS);
+        assert_eq!(ledbat.on_timeout(8 * MSS), MSS);
+    }
+
+    #[test]
+    fn test_ledbat_gain() {
+        let mut ledbat = Ledbat;
+        let mut config: UtpConfig = Default::default();
+        let cwnd = 10 * MSS;
+        let ack = AckSample {
+            bytes_acked: MSS,
+            queuing_delay: 0,
+            flight_size: cwnd,
+        };
+
+        // Below the target, the window grows by a share of a packet
+        // proportional to the gain
+        config.gain = 1.0;
+        let slow = ledbat.on_ack(cwnd, &ack, &config);
+        config.gain = 2.0;
+        let fast = ledbat.on_ack(cwnd, &ack, &config);
+        assert_eq!(slow, cwnd + MSS / 10);
+        assert_eq!(fast, cwnd + MSS / 5);
+
+        // Above the target, it shrinks just as much
+        let congested = AckSample { queuing_delay: 2 * config.target_delay as i64, ..ack };
+        config.gain = 1.0;
+        assert_eq!(ledbat.on_ack(cwnd, &congested, &config), cwnd - MSS / 10);
+
+        // Right on target, it holds steady
+        let steady = AckSample { queuing_delay: config.target_delay as i64, ..ack };
+        assert_eq!(ledbat.on_ack(cwnd, &steady, &config), cwnd);
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::old_io::net::ip::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::SteadyTime;
+
+/// How long a base delay remains valid, in minutes, matching the length of a
+/// socket's own base delay history.
+const HISTORY_MINUTES: i64 = 10;
+
+/// Base delays shared by the connections to each remote host.
+///
+/// A new connection starts without any delay history, so its congestion
+/// controller has no baseline until enough packets went by. When several
+/// sockets are given the same cache (see `UtpSocketBuilder::delay_history`),
+/// each one records the lowest delay it observed toward its remote host and
+/// later connections to that host start from it.
+///
+/// Delays are measured against the remote host's clock, so they are only
+/// shared between connections to the same IP address.
+#[derive(Clone)]
+pub struct DelayHistoryCache {
+    delays: Arc<Mutex<HashMap<IpAddr, (i64, SteadyTime)>>>,
+}
+
+impl DelayHistoryCache {
+    /// Create an empty cache.
+    pub fn new() -> DelayHistoryCache {
+        DelayHistoryCache {
+            delays: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Return the lowest delay recently observed toward `ip`, in
+    /// microseconds.
+    pub fn get(&self, ip: IpAddr) -> Option<i64> {
+        let delays = self.delays.lock().unwrap();
+        match delays.get(&ip) {
+            Some(&(delay, at)) if SteadyTime::now() - at < Duration::minutes(HISTORY_MINUTES) => {
+                Some(delay)
+            },
+            _ => None,
+        }
+    }
+
+    /// Record a delay observed toward `ip`, in microseconds, keeping the
+    /// lowest one.
+    pub fn update(&self, ip: IpAddr, delay: i64) {
+        let mut delays = self.delays.lock().unwrap();
+        let now = SteadyTime::now();
+        let lower = match delays.get(&ip) {
+            Some(&(current, at)) => delay <= current || now - at >= Duration::minutes(HISTORY_MINUTES),
+            None => true,
+        };
+        if lower {
+            delays.insert(ip, (delay, now));
+        }
+    }
+
+    /// Forget the delays observed toward `ip`.
+    pub fn remove(&self, ip: IpAddr) {
+        self.delays.lock().unwrap().remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DelayHistoryCache;
+    use std::old_io::net::ip::Ipv4Addr;
+
+    #[test]
+    fn test_keeps_lowest_delay() {
+        let cache = DelayHistoryCache::new();
+        let ip = Ipv4Addr(127, 0, 0, 1);
+        assert_eq!(cache.get(ip), None);
+
+        cache.update(ip, 300);
+        cache.update(ip, 100);
+        cache.update(ip, 200);
+        assert_eq!(cache.get(ip), Some(100));
+
+        // Clones share the same history
+        let other = cache.clone();
+        other.update(ip, 50);
+        assert_eq!(cache.get(ip), Some(50));
+        assert_eq!(cache.get(Ipv4Addr(10, 0, 0, 1)), None);
+    }
+}
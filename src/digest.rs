@@ -0,0 +1,100 @@
+//! End-to-end digests of the byte stream carried by a connection.
+//!
+//! Once both peers agreed on it during the handshake, each side hashes the
+//! data written by the application as it is queued, and the data returned to
+//! the application as it is read. The FIN carries the sender's digest in a
+//! digest extension, so the receiver can tell at the end of the stream
+//! whether it read exactly what was written, catching corruption that slipped
+//! through reassembly.
+
+/// Length of the digest extension: the hash of the stream, then its length.
+pub const DIGEST_LEN: usize = 16;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Running hash (64-bit FNV-1a) and length of a byte stream.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct StreamDigest {
+    hash: u64,
+    len: u64,
+}
+
+impl StreamDigest {
+    /// Create the digest of an empty stream.
+    pub fn new() -> StreamDigest {
+        StreamDigest {
+            hash: FNV_OFFSET_BASIS,
+            len: 0,
+        }
+    }
+
+    /// Account for the next bytes of the stream.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data.iter() {
+            self.hash = (self.hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+        self.len += data.len() as u64;
+    }
+
+    /// Return the number of bytes in the stream so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Encode the digest as the payload of a digest extension, in network
+    /// byte order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        (0..DIGEST_LEN).map(|i| {
+            let word = if i < 8 { self.hash } else { self.len };
+            (word >> (56 - 8 * (i % 8))) as u8
+        }).collect()
+    }
+
+    /// Decode the payload of a digest extension, if well-formed.
+    pub fn from_bytes(buf: &[u8]) -> Option<StreamDigest> {
+        if buf.len() < DIGEST_LEN {
+            return None;
+        }
+        let word = |bytes: &[u8]| bytes.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        Some(StreamDigest {
+            hash: word(&buf[..8]),
+            len: word(&buf[8..DIGEST_LEN]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StreamDigest, DIGEST_LEN};
+
+    #[test]
+    fn test_digest_independent_of_chunking() {
+        let mut whole = StreamDigest::new();
+        whole.update(b"hello world");
+
+        let mut chunked = StreamDigest::new();
+        chunked.update(b"hel");
+        chunked.update(b"");
+        chunked.update(b"lo world");
+        assert_eq!(chunked, whole);
+        assert_eq!(whole.len(), 11);
+
+        // Swapped chunks make a different stream
+        let mut swapped = StreamDigest::new();
+        swapped.update(b"lo world");
+        swapped.update(b"hel");
+        assert!(swapped != whole);
+    }
+
+    #[test]
+    fn test_digest_encoding() {
+        let mut digest = StreamDigest::new();
+        digest.update(&[1, 2, 3]);
+        let bytes = digest.to_bytes();
+        assert_eq!(bytes.len(), DIGEST_LEN);
+        assert_eq!(&bytes[8..], &[0, 0, 0, 0, 0, 0, 0, 3][..]);
+        assert_eq!(StreamDigest::from_bytes(&bytes[..]), Some(digest));
+        assert_eq!(StreamDigest::from_bytes(&bytes[..8]), None);
+    }
+}
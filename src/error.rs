@@ -0,0 +1,168 @@
+//! Protocol errors, telling callers why a connection failed.
+//!
+//! Socket methods report failures as `IoError`s, whose kinds only roughly
+//! describe uTP failures; their detail names the `UtpError` behind them. The
+//! socket also keeps the protocol error behind its latest error as a
+//! `UtpError`, which callers may match on to react programmatically. Failed
+//! connection attempts report it through `ConnectError`, as the socket doesn't
+//! come back from `connect`.
+
+use std::fmt;
+use std::old_io::{IoErrorKind, ConnectionReset, ConnectionFailed, TimedOut, OtherIoError, Closed};
+use std::old_io::net::ip::SocketAddr;
+use packet::{PacketType, ParseError};
+
+/// Failures of the uTP protocol.
+///
+/// Every variant carries the id of the connection, as found in the packets
+/// the remote peer sends, and the address of the remote peer.
+#[derive(PartialEq,Eq,Debug,Clone,Copy)]
+pub enum UtpError {
+    /// The remote peer aborted the connection.
+    ConnectionReset {
+        /// Id of the connection
+        connection_id: u16,
+        /// Address of the remote peer
+        peer: SocketAddr,
+    },
+    /// The network kept reporting the remote peer as unreachable.
+    Unreachable {
+        /// Id of the connection
+        connection_id: u16,
+        /// Address of the remote peer
+        peer: SocketAddr,
+    },
+    /// The remote peer didn't accept the connection: its reply to the SYN,
+    /// of the given type, isn't a valid acknowledgement.
+    HandshakeFailed {
+        /// Id of the connection
+        connection_id: u16,
+        /// Address of the remote peer
+        peer: SocketAddr,
+        /// Type of the reply
+        packet_type: PacketType,
+    },
+    /// The remote peer didn't reply in time, to the SYN or for longer than
+    /// `UtpConfig::idle_timeout`.
+    TimedOut {
+        /// Id of the connection
+        connection_id: u16,
+        /// Address of the remote peer
+        peer: SocketAddr,
+    },
+    /// The remote peer sent a packet that couldn't be decoded.
+    MalformedPacket {
+        /// Id of the connection
+        connection_id: u16,
+        /// Address of the remote peer
+        peer: SocketAddr,
+        /// Why decoding failed
+        cause: ParseError,
+    },
+    /// The data read doesn't match the digest the remote peer sent with its
+    /// FIN.
+    DigestMismatch {
+        /// Id of the connection
+        connection_id: u16,
+        /// Address of the remote peer
+        peer: SocketAddr,
+    },
+    /// The connection was closed before the operation completed.
+    Closed {
+        /// Id of the connection
+        connection_id: u16,
+        /// Address of the remote peer
+        peer: SocketAddr,
+    },
+}
+
+impl UtpError {
+    /// Return the id of the failed connection.
+    pub fn connection_id(&self) -> u16 {
+        match *self {
+            UtpError::ConnectionReset { connection_id, .. } |
+            UtpError::Unreachable { connection_id, .. } |
+            UtpError::HandshakeFailed { connection_id, .. } |
+            UtpError::TimedOut { connection_id, .. } |
+            UtpError::MalformedPacket { connection_id, .. } |
+            UtpError::DigestMismatch { connection_id, .. } |
+            UtpError::Closed { connection_id, .. } => connection_id,
+        }
+    }
+
+    /// Return the address of the remote peer of the failed connection.
+    pub fn peer(&self) -> SocketAddr {
+        match *self {
+            UtpError::ConnectionReset { peer, .. } |
+            UtpError::Unreachable { peer, .. } |
+            UtpError::HandshakeFailed { peer, .. } |
+            UtpError::TimedOut { peer, .. } |
+            UtpError::MalformedPacket { peer, .. } |
+            UtpError::DigestMismatch { peer, .. } |
+            UtpError::Closed { peer, .. } => peer,
+        }
+    }
+
+    /// Return the kind of the `IoError` reporting this error.
+    pub fn kind(&self) -> IoErrorKind {
+        match *self {
+            UtpError::ConnectionReset { .. } | UtpError::Unreachable { .. } => ConnectionReset,
+            UtpError::HandshakeFailed { .. } | UtpError::MalformedPacket { .. } => ConnectionFailed,
+            UtpError::TimedOut { .. } => TimedOut,
+            UtpError::DigestMismatch { .. } => OtherIoError,
+            UtpError::Closed { .. } => Closed,
+        }
+    }
+
+    /// Return a short description of the error.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            UtpError::ConnectionReset { .. } => "Remote host aborted connection",
+            UtpError::Unreachable { .. } => "Remote peer is unreachable",
+            UtpError::HandshakeFailed { .. } => "The remote peer sent an invalid reply",
+            UtpError::TimedOut { .. } => "The remote peer didn't reply",
+            UtpError::MalformedPacket { .. } => "The remote peer sent a malformed packet",
+            UtpError::DigestMismatch { .. } => "Stream digest mismatch",
+            UtpError::Closed { .. } => "Connection closed",
+        }
+    }
+}
+
+impl fmt::Display for UtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.description()));
+        match *self {
+            UtpError::HandshakeFailed { packet_type, .. } => try!(write!(f, " ({:?})", packet_type)),
+            UtpError::MalformedPacket { cause, .. } => try!(write!(f, " ({})", cause)),
+            _ => {}
+        }
+        write!(f, " on connection {} with {}", self.connection_id(), self.peer())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::UtpError;
+    use std::old_io::{ConnectionFailed, TimedOut};
+    use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+    use packet::ParseError;
+
+    #[test]
+    fn test_error_details() {
+        let peer = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 6881 };
+        let error = UtpError::MalformedPacket {
+            connection_id: 42,
+            peer: peer,
+            cause: ParseError::InvalidPacketType,
+        };
+        assert_eq!(error.connection_id(), 42);
+        assert_eq!(error.peer(), peer);
+        assert_eq!(error.kind(), ConnectionFailed);
+        assert_eq!(format!("{}", error),
+                   "The remote peer sent a malformed packet (Unknown packet type) \
+                    on connection 42 with 127.0.0.1:6881");
+
+        let error = UtpError::TimedOut { connection_id: 7, peer: peer };
+        assert_eq!(error.kind(), TimedOut);
+    }
+}
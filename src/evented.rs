@@ -0,0 +1,35 @@
+//! Registration of uTP sockets with mio event loops.
+//!
+//! A socket is registered through the file descriptor of its transport, which
+//! becomes readable when a datagram arrives for it. Sockets whose transport
+//! has none, such as those accepted by a listener, can't be registered and
+//! should be polled with `UtpSocket::wait_readable` instead.
+
+use std::io;
+use std::os::unix::Fd;
+use mio::{Evented, Selector, Token, Interest, PollOpt};
+use socket::UtpSocket;
+
+impl Evented for UtpSocket {
+    fn register(&self, selector: &mut Selector, token: Token, interest: Interest,
+                opts: PollOpt) -> io::Result<()> {
+        selector.register(try!(descriptor(self)), token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: Interest,
+                  opts: PollOpt) -> io::Result<()> {
+        selector.reregister(try!(descriptor(self)), token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        selector.deregister(try!(descriptor(self)))
+    }
+}
+
+/// Return the file descriptor to watch for `socket`.
+fn descriptor(socket: &UtpSocket) -> io::Result<Fd> {
+    socket.as_raw_fd().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "The socket's transport has no file descriptor",
+                       None)
+    })
+}
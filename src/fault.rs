@@ -0,0 +1,141 @@
+//! Fault injection, for exercising the error handling paths of sockets in
+//! tests.
+
+use std::cmp::min;
+use std::old_io::{IoResult, IoError, BrokenPipe};
+use std::old_io::net::ip::SocketAddr;
+use std::old_io::net::udp::UdpSocket;
+use std::sync::{Arc, Mutex};
+use packet::HEADER_SIZE;
+use transport::DatagramTransport;
+
+/// Faults waiting to be injected into sent datagrams.
+struct Faults {
+    /// Number of upcoming sends to let through before failing any
+    passed_sends: usize,
+    /// Number of sends to fail after those
+    failed_sends: usize,
+    /// Number of upcoming datagrams to truncate
+    short_sends: usize,
+    /// Added to the timestamp of every packet sent
+    timestamp_offset: u32,
+    /// Size above which datagrams are silently dropped, if any
+    max_datagram: Option<usize>,
+}
+
+/// Handle injecting faults into a `FaultyTransport`, usable while a socket
+/// owns the transport.
+#[derive(Clone)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultInjector {
+    /// Make the next `count` sends fail.
+    pub fn fail_sends(&self, count: usize) {
+        self.fail_sends_after(0, count);
+    }
+
+    /// Make `count` sends fail, after letting the next `skip` through.
+    pub fn fail_sends_after(&self, skip: usize, count: usize) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.passed_sends = skip;
+        faults.failed_sends = count;
+    }
+
+    /// Truncate the next `count` datagrams within the packet header.
+    ///
+    /// uTP has no length field, so a datagram cut past the header would go
+    /// unnoticed and only the header is truncated.
+    pub fn truncate_sends(&self, count: usize) {
+        self.faults.lock().unwrap().short_sends = count;
+    }
+
+    /// Add `offset` to the timestamp of every packet sent from now on, as a
+    /// clock jumping would.
+    pub fn skew_timestamps(&self, offset: u32) {
+        self.faults.lock().unwrap().timestamp_offset = offset;
+    }
+
+    /// Silently drop every datagram larger than `size` bytes from now on, as
+    /// a path MTU black hole would, or stop dropping them if `None`.
+    pub fn drop_sends_larger_than(&self, size: Option<usize>) {
+        self.faults.lock().unwrap().max_datagram = size;
+    }
+}
+
+/// UDP transport injecting faults into the datagrams it sends.
+pub struct FaultyTransport {
+    socket: UdpSocket,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyTransport {
+    /// Bind a transport to `addr`, returning it along with the handle
+    /// injecting faults into it.
+    pub fn bind(addr: SocketAddr) -> IoResult<(FaultyTransport, FaultInjector)> {
+        let faults = Arc::new(Mutex::new(Faults {
+            passed_sends: 0,
+            failed_sends: 0,
+            short_sends: 0,
+            timestamp_offset: 0,
+            max_datagram: None,
+        }));
+        let transport = FaultyTransport {
+            socket: try!(UdpSocket::bind(addr)),
+            faults: faults.clone(),
+        };
+        Ok((transport, FaultInjector { faults: faults }))
+    }
+}
+
+impl DatagramTransport for FaultyTransport {
+    fn send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<()> {
+        let mut faults = self.faults.lock().unwrap();
+        if faults.passed_sends > 0 {
+            faults.passed_sends -= 1;
+        } else if faults.failed_sends > 0 {
+            faults.failed_sends -= 1;
+            return Err(IoError {
+                kind: BrokenPipe,
+                desc: "Injected send failure",
+                detail: None,
+            });
+        }
+
+        if faults.max_datagram.map_or(false, |size| buf.len() > size) {
+            return Ok(());
+        }
+
+        let mut datagram = buf.to_vec();
+        if faults.short_sends > 0 {
+            faults.short_sends -= 1;
+            datagram.truncate(min(buf.len(), HEADER_SIZE) / 2);
+        }
+        if faults.timestamp_offset != 0 && datagram.len() >= HEADER_SIZE {
+            let timestamp = (datagram[4] as u32) << 24 | (datagram[5] as u32) << 16 |
+                (datagram[6] as u32) << 8 | datagram[7] as u32;
+            let timestamp = timestamp.wrapping_add(faults.timestamp_offset);
+            for i in 0..4 {
+                datagram[4 + i] = (timestamp >> (24 - 8 * i)) as u8;
+            }
+        }
+        self.socket.send_to(&datagram[..], dst)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.socket.set_read_timeout(timeout_ms)
+    }
+
+    fn poll(&mut self, timeout_ms: Option<u64>) -> IoResult<bool> {
+        DatagramTransport::poll(&mut self.socket, timeout_ms)
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+}
@@ -0,0 +1,140 @@
+use std::cmp::min;
+
+const SELECTIVE_ACK: u8 = 1;
+const CHECKSUM: u8 = 2;
+const ENCRYPTION: u8 = 4;
+const COMPRESSION: u8 = 8;
+const PARITY: u8 = 16;
+const DIGEST: u8 = 32;
+
+/// Length of the features extension, in bytes.
+pub const FEATURES_LEN: usize = 8;
+
+/// Optional protocol features, announced by each peer during the handshake.
+///
+/// Once a connection is established, the socket holds the features both peers
+/// support, as returned by `negotiate`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Features {
+    /// Selective acknowledgements.
+    pub selective_ack: bool,
+    /// Checksums of packet contents.
+    pub checksum: bool,
+    /// Payload encryption.
+    pub encryption: bool,
+    /// Payload compression.
+    pub compression: bool,
+    /// Recovery of lost data packets from parity packets.
+    pub parity: bool,
+    /// End-to-end digest of the byte stream, checked at the end of the
+    /// connection.
+    pub digest: bool,
+    /// Largest receive window the peer will advertise, in bytes.
+    pub max_window: u32,
+}
+
+impl Features {
+    /// Features assumed of a peer that doesn't announce any, i.e., plain
+    /// BEP 29 support.
+    pub fn baseline() -> Features {
+        Features {
+            selective_ack: true,
+            checksum: false,
+            encryption: false,
+            compression: false,
+            parity: false,
+            digest: false,
+            max_window: ::std::u32::MAX,
+        }
+    }
+
+    /// Return the features supported by both `self` and `other`.
+    pub fn negotiate(&self, other: &Features) -> Features {
+        Features {
+            selective_ack: self.selective_ack && other.selective_ack,
+            checksum: self.checksum && other.checksum,
+            encryption: self.encryption && other.encryption,
+            compression: self.compression && other.compression,
+            parity: self.parity && other.parity,
+            digest: self.digest && other.digest,
+            max_window: min(self.max_window, other.max_window),
+        }
+    }
+
+    /// Encode the features as the payload of a features extension.
+    ///
+    /// The first byte holds the feature flags, the next three are reserved and
+    /// the last four hold the maximum window in network byte order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.selective_ack { flags |= SELECTIVE_ACK; }
+        if self.checksum { flags |= CHECKSUM; }
+        if self.encryption { flags |= ENCRYPTION; }
+        if self.compression { flags |= COMPRESSION; }
+        if self.parity { flags |= PARITY; }
+        if self.digest { flags |= DIGEST; }
+
+        let w = self.max_window;
+        vec!(flags, 0, 0, 0, (w >> 24) as u8, (w >> 16) as u8, (w >> 8) as u8, w as u8)
+    }
+
+    /// Decode the payload of a features extension, if well-formed.
+    pub fn from_bytes(buf: &[u8]) -> Option<Features> {
+        if buf.len() < FEATURES_LEN {
+            return None;
+        }
+
+        let flags = buf[0];
+        let max_window = buf[4..8].iter().fold(0u32, |acc, &b| acc << 8 | b as u32);
+        Some(Features {
+            selective_ack: flags & SELECTIVE_ACK != 0,
+            checksum: flags & CHECKSUM != 0,
+            encryption: flags & ENCRYPTION != 0,
+            compression: flags & COMPRESSION != 0,
+            parity: flags & PARITY != 0,
+            digest: flags & DIGEST != 0,
+            max_window: max_window,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Features, FEATURES_LEN};
+
+    #[test]
+    fn test_features_encoding() {
+        let features = Features {
+            selective_ack: true,
+            checksum: false,
+            encryption: true,
+            compression: true,
+            parity: true,
+            digest: false,
+            max_window: 0x01020304,
+        };
+        let bytes = features.to_bytes();
+        assert_eq!(bytes.len(), FEATURES_LEN);
+        assert_eq!(bytes, vec!(29, 0, 0, 0, 1, 2, 3, 4));
+        assert_eq!(Features::from_bytes(&bytes[..]), Some(features));
+        assert_eq!(Features::from_bytes(&bytes[..4]), None);
+    }
+
+    #[test]
+    fn test_features_negotiation() {
+        let local = Features {
+            selective_ack: true,
+            checksum: true,
+            encryption: false,
+            compression: true,
+            parity: true,
+            digest: true,
+            max_window: 1024,
+        };
+        let negotiated = local.negotiate(&Features::baseline());
+        assert!(negotiated.selective_ack);
+        assert!(!negotiated.checksum && !negotiated.encryption && !negotiated.compression);
+        assert!(!negotiated.parity && !negotiated.digest);
+        assert_eq!(negotiated.max_window, 1024);
+    }
+}
@@ -0,0 +1,157 @@
+//! Forward error correction with XOR parity.
+//!
+//! Once both peers agreed on it during the handshake, a sender configured with
+//! a parity group size follows every group of data packets with a parity
+//! packet: a STATE packet carrying a parity extension, which identifies the
+//! group, and the XOR of the group's payloads as payload. A receiver missing a
+//! single packet of the group rebuilds it from the parity and the other
+//! packets, without waiting a round trip for the retransmission.
+
+use std::iter::repeat;
+
+/// Length of the parity extension: sequence number of the group's first
+/// packet, number of packets in the group and XOR of their payload lengths.
+pub const PARITY_LEN: usize = 5;
+
+/// Largest number of data packets protected by a single parity packet.
+pub const MAX_GROUP_SIZE: u8 = 32;
+
+/// Accumulates the parity of consecutive data packets.
+pub struct ParityEncoder {
+    group_size: u8,
+    first_seq_nr: u16,
+    count: u8,
+    len_xor: u16,
+    data: Vec<u8>,
+}
+
+impl ParityEncoder {
+    /// Create an encoder emitting a parity packet every `group_size` data
+    /// packets.
+    pub fn new(group_size: u8) -> ParityEncoder {
+        ParityEncoder {
+            group_size: group_size,
+            first_seq_nr: 0,
+            count: 0,
+            len_xor: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// Return the number of data packets in each group.
+    pub fn group_size(&self) -> u8 {
+        self.group_size
+    }
+
+    /// Account for the first transmission of a data packet.
+    ///
+    /// Returns the parity extension and payload once the packet completes a
+    /// group. A packet not following the previous one starts a new group.
+    pub fn add(&mut self, seq_nr: u16, payload: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.count > 0 && seq_nr != self.first_seq_nr.wrapping_add(self.count as u16) {
+            self.count = 0;
+        }
+        if self.count == 0 {
+            self.first_seq_nr = seq_nr;
+            self.len_xor = 0;
+            self.data.clear();
+        }
+
+        self.count += 1;
+        self.len_xor ^= payload.len() as u16;
+        xor_into(&mut self.data, payload);
+
+        if self.count < self.group_size {
+            return None;
+        }
+
+        let extension = vec!((self.first_seq_nr >> 8) as u8, self.first_seq_nr as u8, self.count,
+                             (self.len_xor >> 8) as u8, self.len_xor as u8);
+        self.count = 0;
+        Some((extension, self.data.clone()))
+    }
+}
+
+/// Decode a parity extension into the sequence number of the group's first
+/// packet, the number of packets in the group and the XOR of their payload
+/// lengths.
+pub fn decode_parity(extension: &[u8]) -> Option<(u16, u8, u16)> {
+    if extension.len() < PARITY_LEN || extension[2] == 0 || extension[2] > MAX_GROUP_SIZE {
+        return None;
+    }
+    let first_seq_nr = (extension[0] as u16) << 8 | extension[1] as u16;
+    let len_xor = (extension[3] as u16) << 8 | extension[4] as u16;
+    Some((first_seq_nr, extension[2], len_xor))
+}
+
+/// Rebuild the single missing payload of a group from its parity and the
+/// payloads of the other packets.
+///
+/// Returns `None` if the parity is inconsistent with the other payloads.
+pub fn recover<'a, I>(len_xor: u16, parity: &[u8], others: I) -> Option<Vec<u8>>
+    where I: Iterator<Item = &'a [u8]>
+{
+    let mut len = len_xor;
+    let mut data = parity.to_vec();
+    for payload in others {
+        len ^= payload.len() as u16;
+        xor_into(&mut data, payload);
+    }
+
+    if len as usize > data.len() {
+        return None;
+    }
+    data.truncate(len as usize);
+    Some(data)
+}
+
+/// XOR `src` into `dst`, growing it as needed.
+fn xor_into(dst: &mut Vec<u8>, src: &[u8]) {
+    if src.len() > dst.len() {
+        let len = dst.len();
+        dst.extend(repeat(0).take(src.len() - len));
+    }
+    for (d, &s) in dst.iter_mut().zip(src.iter()) {
+        *d ^= s;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ParityEncoder, decode_parity, recover};
+
+    #[test]
+    fn test_recover_any_single_loss() {
+        let payloads: Vec<Vec<u8>> = vec!(vec!(1, 2, 3, 4), vec!(5, 6), vec!(7, 8, 9), vec!());
+        let mut encoder = ParityEncoder::new(4);
+
+        let mut parity = None;
+        for (i, payload) in payloads.iter().enumerate() {
+            parity = encoder.add(65534u16.wrapping_add(i as u16), &payload[..]);
+            assert_eq!(parity.is_some(), i == 3);
+        }
+        let (extension, data) = parity.unwrap();
+        let (first_seq_nr, count, len_xor) = decode_parity(&extension[..]).unwrap();
+        assert_eq!((first_seq_nr, count), (65534, 4));
+
+        for lost in 0..payloads.len() {
+            let others = payloads.iter().enumerate()
+                .filter(|&(i, _)| i != lost)
+                .map(|(_, payload)| &payload[..]);
+            assert_eq!(recover(len_xor, &data[..], others), Some(payloads[lost].clone()));
+        }
+    }
+
+    #[test]
+    fn test_gap_starts_new_group() {
+        let mut encoder = ParityEncoder::new(2);
+        assert!(encoder.add(1, &[1]).is_none());
+        assert!(encoder.add(3, &[2]).is_none());
+        let (extension, data) = encoder.add(4, &[4]).unwrap();
+        assert_eq!(decode_parity(&extension[..]), Some((3, 2, 0)));
+        assert_eq!(data, vec!(6));
+
+        assert_eq!(decode_parity(&[0, 1, 0, 0, 0]), None);
+        assert_eq!(decode_parity(&[0, 1, 2]), None);
+    }
+}
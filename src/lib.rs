@@ -1,6 +1,28 @@
 //! Implementation of the Micro Transport Protocol.[^spec]
 //!
 //! [^spec]: http://www.bittorrent.org/beps/bep_0029.html
+//!
+//! # Examples
+//!
+//! ```
+//! use utp::UtpStream;
+//! use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+//! use std::thread;
+//!
+//! // Port 0 means the operating system gets to choose it
+//! let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+//! let mut server = UtpStream::bind(addr).unwrap();
+//! let addr = server.local_addr().unwrap();
+//!
+//! thread::spawn(move || {
+//!     let mut client = UtpStream::connect(addr).unwrap();
+//!     client.write_all(b"Hi there!").unwrap();
+//!     client.close().unwrap();
+//! });
+//!
+//! let received = server.read_to_end().unwrap();
+//! assert_eq!(&received[..], &b"Hi there!"[..]);
+//! ```
 
 //   __________  ____  ____
 //  /_  __/ __ \/ __ \/ __ \
@@ -15,17 +37,49 @@
 // - Path MTU discovery (RFC4821)
 
 #![deny(missing_docs)]
+#![cfg_attr(feature = "serde_serialization", feature(custom_derive, plugin))]
+#![cfg_attr(feature = "serde_serialization", plugin(serde_macros))]
 
 extern crate rand;
+extern crate libc;
 extern crate time;
 #[macro_use] extern crate log;
+#[cfg(feature = "serde_serialization")] extern crate serde;
+#[cfg(feature = "mio")] extern crate mio;
 
 // Public API
-pub use socket::UtpSocket;
+pub use socket::{UtpSocket, UtpSocketBuilder, ConnectionRequest, CloseReason, BufferLevel,
+                  WriteHandle, KeepAliveHandle, Shutdown, Reactor, ConnectError};
 pub use stream::UtpStream;
+pub use listener::UtpListener;
+pub use packet::{PacketSummary, PacketType, ExtensionType, ParseError};
+pub use config::{UtpConfig, ParsingMode, OverflowPolicy};
+pub use stats::{SocketStats, LatencyHistogram, DropCounters};
+pub use features::Features;
+pub use delay_history::DelayHistoryCache;
+pub use transport::DatagramTransport;
+pub use socks::Socks5Transport;
+pub use error::UtpError;
+pub use congestion::{CongestionControl, Ledbat, AckSample};
 
 mod util;
+mod config;
+mod stats;
+mod features;
+mod delay_history;
+mod congestion;
+mod compression;
+mod fec;
+mod digest;
+mod error;
+mod transport;
+mod socks;
 mod bit_iterator;
 mod packet;
 mod socket;
 mod stream;
+mod listener;
+#[cfg(all(feature = "mio", unix))]
+mod evented;
+#[cfg(test)]
+mod fault;
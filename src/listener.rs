@@ -0,0 +1,920 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::FromError;
+use std::old_io::{IoResult, IoError, TimedOut, Closed, ConnectionFailed, IoUnavailable};
+use std::old_io::net::ip::SocketAddr;
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::timer;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver, TryRecvError, TrySendError};
+use std::thread;
+use std::time::Duration;
+use time::SteadyTime;
+use config::UtpConfig;
+use packet::{PacketRef, PacketType};
+use socket::{UtpSocket, UtpSocketBuilder, ConnectError};
+use transport::DatagramTransport;
+use util::{is_icmp_error, TIMER_GRANULARITY};
+
+const BUF_SIZE: usize = 1500;
+const DISPATCH_TIMEOUT: u64 = 500; // milliseconds
+const MAX_QUEUED: usize = 64; // datagrams waiting to be sent, per connection
+const MAX_BACKLOG: usize = 128; // connection requests waiting to be accepted
+const MAX_BUFFERED: usize = 256; // datagrams waiting to be received, per connection
+
+/// Datagram received by the listener, along with its source.
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Connection request waiting in the accept queue: the remote peer and the
+/// datagrams it sent, starting with its SYN.
+type Pending = (SocketAddr, Receiver<Datagram>, Arc<AtomicBool>);
+
+/// Routes of the accepted or pending connections, by remote peer and
+/// connection id.
+type Routes = HashMap<(SocketAddr, u16), (SyncSender<Datagram>, Arc<AtomicBool>)>;
+
+/// Error that stopped the dispatching thread, if any, reported to `accept`
+/// and to the sockets in place of the end of the listener.
+type Failure = Arc<Mutex<Option<IoError>>>;
+
+/// A uTP listener accepting connections from many remote peers on a single
+/// UDP port.
+///
+/// Connections to remote peers can be opened from the same port too, as
+/// BitTorrent clients do, so that a single port serves every connection. A
+/// background thread reads every datagram arriving on the port and hands it
+/// to the connection it belongs to, so sockets keep working independently of
+/// each other and of the listener. Another one sends the
+/// datagrams of every connection, which take turns so that a bulk transfer
+/// can't starve the others. Both threads end once the listener and every
+/// socket it accepted are dropped.
+///
+/// At most 128 connection requests wait to be accepted, and at most 256
+/// datagrams wait to be received by each connection; anything more is
+/// dropped, so that a flood of SYNs or data can't exhaust memory. Remote
+/// peers send their SYN again later, as for any lost packet.
+///
+/// # Examples
+///
+/// ```
+/// use utp::{UtpListener, UtpSocket};
+/// use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+/// use std::thread;
+///
+/// // Port 0 means the operating system gets to choose it
+/// let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+/// let mut listener = UtpListener::bind(addr).unwrap();
+/// let server_addr = listener.local_addr().unwrap();
+///
+/// thread::spawn(move || {
+///     let client = UtpSocket::bind(addr).unwrap();
+///     let mut client = client.connect(server_addr).unwrap();
+///     client.send(b"Hello").unwrap();
+///     client.close().unwrap();
+/// });
+///
+/// let (mut socket, _src) = listener.accept().unwrap();
+/// let mut buf = [0; 1500];
+/// let read = socket.recv(&mut buf).unwrap();
+/// assert_eq!(&buf[..read], &b"Hello"[..]);
+/// ```
+pub struct UtpListener {
+    /// Handle of the UDP socket, for sending and for cloning into accepted
+    /// sockets
+    socket: UdpSocket,
+    /// Connection requests, in order of arrival
+    pending: Receiver<Pending>,
+    /// Routes shared with the dispatching thread, for registering outgoing
+    /// connections
+    routes: Arc<Mutex<Routes>>,
+    /// Tells the dispatching thread the listener is gone
+    closed: Arc<AtomicBool>,
+    failure: Failure,
+    /// Datagrams of the accepted sockets waiting to be sent
+    scheduler: Arc<SendScheduler>,
+    /// Configuration of the sockets accepted from now on
+    config: UtpConfig,
+}
+
+impl UtpListener {
+    /// Create a listener bound to the given address.
+    #[unstable]
+    pub fn bind(addr: SocketAddr) -> IoResult<UtpListener> {
+        let socket = try!(UdpSocket::bind(addr));
+        let mut reader = socket.clone();
+        reader.set_read_timeout(Some(DISPATCH_TIMEOUT));
+
+        let (tx, rx) = sync_channel(MAX_BACKLOG);
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let dispatch_routes = routes.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        let listener_closed = closed.clone();
+        let failure = Arc::new(Mutex::new(None));
+        let dispatch_failure = failure.clone();
+        thread::spawn(move || dispatch(reader, dispatch_routes, tx, listener_closed,
+                                       dispatch_failure));
+
+        let scheduler = Arc::new(SendScheduler {
+            queues: Mutex::new(SendQueues::new()),
+            changed: Condvar::new(),
+        });
+        let writer = socket.clone();
+        let writer_scheduler = scheduler.clone();
+        thread::spawn(move || send_queued(writer, writer_scheduler));
+
+        Ok(UtpListener {
+            socket: socket,
+            pending: rx,
+            routes: routes,
+            closed: closed,
+            failure: failure,
+            scheduler: scheduler,
+            config: UtpConfig::default(),
+        })
+    }
+
+    /// Wait for a connection request and accept it, returning a connected
+    /// socket and the address of the remote peer.
+    #[unstable]
+    pub fn accept(&mut self) -> IoResult<(UtpSocket, SocketAddr)> {
+        let (src, incoming, dropped) = match self.pending.recv() {
+            Ok(pending) => pending,
+            Err(_) => return Err(listener_error(&self.failure)),
+        };
+
+        let id = self.scheduler.queues.lock().unwrap().register();
+        let transport = ListenerTransport {
+            socket: self.socket.clone(),
+            scheduler: self.scheduler.clone(),
+            id: id,
+            incoming: incoming,
+            polled: None,
+            timeout: None,
+            failure: self.failure.clone(),
+            dropped: dropped,
+            outgoing: None,
+        };
+        let mut socket = try!(UtpSocketBuilder::new().config(self.config).bind_transport(transport));
+        try!(socket.accept_inspect());
+        try!(socket.accept_pending());
+        Ok((socket, src))
+    }
+
+    /// Open a connection to a remote peer from the listening port.
+    ///
+    /// The connection uses the configuration of accepted sockets, and works
+    /// like one once established.
+    #[unstable]
+    pub fn connect(&mut self, dst: SocketAddr) -> IoResult<UtpSocket> {
+        self.try_connect(dst).map_err(FromError::from_error)
+    }
+
+    /// Open a connection like `connect`, but on failure give back the socket
+    /// along with the protocol error behind the failure, if any (see
+    /// `UtpSocket::try_connect`).
+    #[unstable]
+    pub fn try_connect(&mut self, dst: SocketAddr) -> Result<UtpSocket, ConnectError> {
+        let (tx, rx) = sync_channel(MAX_BUFFERED);
+        let id = self.scheduler.queues.lock().unwrap().register();
+        let transport = ListenerTransport {
+            socket: self.socket.clone(),
+            scheduler: self.scheduler.clone(),
+            id: id,
+            incoming: rx,
+            polled: None,
+            timeout: None,
+            failure: self.failure.clone(),
+            dropped: Arc::new(AtomicBool::new(false)),
+            outgoing: Some((self.routes.clone(), tx)),
+        };
+        let socket = try!(UtpSocketBuilder::new().config(self.config).bind_transport(transport));
+        socket.try_connect(dst)
+    }
+
+    /// Set the configuration of the sockets accepted from now on.
+    ///
+    /// Fails with `InvalidInput` if a parameter lies outside of its safe
+    /// range.
+    #[unstable]
+    pub fn set_config(&mut self, config: UtpConfig) -> IoResult<()> {
+        try!(config.validate());
+        self.config = config;
+        Ok(())
+    }
+
+    /// Return the local address the listener is bound to.
+    #[unstable]
+    pub fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+}
+
+impl Drop for UtpListener {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.scheduler.queues.lock().unwrap().handles -= 1;
+        self.scheduler.changed.notify_all();
+    }
+}
+
+/// Read datagrams from the listening socket and route them to their
+/// connections, queueing SYNs of new ones for `accept`.
+///
+/// Stops once nothing is left to route to, or once the listening socket
+/// fails. The error is then kept in `failure` and every route dropped, so
+/// that `accept` and the sockets waiting for datagrams report it.
+fn dispatch(mut socket: UdpSocket, routes: Arc<Mutex<Routes>>, pending: SyncSender<Pending>,
+            listener_closed: Arc<AtomicBool>, failure: Failure) {
+    let mut buf = [0; BUF_SIZE];
+
+    loop {
+        let (read, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind == TimedOut || is_icmp_error(e) => {
+                // Stop once nothing is left to route to
+                let mut routes = routes.lock().unwrap();
+                forget_dropped(&mut *routes);
+                if listener_closed.load(Ordering::SeqCst) && routes.is_empty() {
+                    return;
+                }
+                continue;
+            },
+            Err(e) => {
+                debug!("listening socket failed: {}", e);
+                *failure.lock().unwrap() = Some(e);
+                routes.lock().unwrap().clear();
+                return;
+            }
+        };
+
+        let (ty, connection_id) = match PacketRef::new(&buf[..read]) {
+            Ok(packet) => (packet.get_type(), packet.connection_id()),
+            Err(e) => {
+                debug!("ignoring invalid packet from {}: {}", src, e);
+                continue;
+            }
+        };
+        let datagram = (buf[..read].to_vec(), src);
+
+        let mut routes = routes.lock().unwrap();
+        let delivered = match routes.get(&(src, connection_id)) {
+            Some(&(ref tx, ref dropped)) => match tx.try_send(datagram.clone()) {
+                Ok(()) => Some(true),
+                // Like a full socket buffer, a full queue loses datagrams
+                Err(TrySendError::Full(_)) => {
+                    debug!("receive queue of connection {} with {} full, dropping datagram",
+                           connection_id, src);
+                    Some(true)
+                },
+                // Nobody receives on the channel once the connection's socket,
+                // or the listener holding it in the accept queue, is dropped
+                Err(TrySendError::Disconnected(_)) => {
+                    dropped.store(true, Ordering::SeqCst);
+                    Some(false)
+                },
+            },
+            None => None,
+        };
+        match delivered {
+            Some(true) => continue,
+            // The datagram may start a new connection reusing the id
+            Some(false) => forget_dropped(&mut *routes),
+            None => {},
+        }
+
+        if ty != PacketType::Syn || listener_closed.load(Ordering::SeqCst) {
+            debug!("ignoring {:?} packet from {} for unknown connection", ty, src);
+            continue;
+        }
+
+        let (tx, rx) = sync_channel(MAX_BUFFERED);
+        let dropped = Arc::new(AtomicBool::new(false));
+        let _ = tx.try_send(datagram);
+        match pending.try_send((src, rx, dropped.clone())) {
+            // The remote peer sends its SYN with the connection id it receives
+            // on, and everything else with the next one
+            Ok(()) => {
+                routes.insert((src, connection_id), (tx.clone(), dropped.clone()));
+                routes.insert((src, connection_id.wrapping_add(1)), (tx, dropped));
+            },
+            Err(TrySendError::Full(_)) => {
+                debug!("accept backlog full, ignoring SYN from {}", src);
+            },
+            // The listener was dropped meanwhile
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
+
+/// Return the error to report once the dispatching thread stopped: the one
+/// that stopped it, if any.
+fn listener_error(failure: &Failure) -> IoError {
+    match *failure.lock().unwrap() {
+        Some(ref e) => e.clone(),
+        None => IoError {
+            kind: Closed,
+            desc: "Listener socket closed",
+            detail: None,
+        },
+    }
+}
+
+/// Forget the connections whose sockets were dropped.
+fn forget_dropped(routes: &mut Routes) {
+    let dropped: Vec<(SocketAddr, u16)> = routes.iter()
+        .filter(|&(_, &(_, ref dropped))| dropped.load(Ordering::SeqCst))
+        .map(|(&key, _)| key)
+        .collect();
+    for key in dropped.iter() {
+        routes.remove(key);
+    }
+}
+
+/// Datagrams waiting to be sent over the listening socket, by connection.
+///
+/// Connections with datagrams waiting take turns, one datagram at a time.
+struct SendQueues {
+    /// Queue of each accepted socket, and whether the socket is still alive
+    queues: HashMap<usize, (VecDeque<Datagram>, bool)>,
+    /// Connections with datagrams waiting, in the order they are served
+    turns: VecDeque<usize>,
+    next_id: usize,
+    /// Number of live accepted sockets, plus one while the listener is
+    handles: usize,
+}
+
+impl SendQueues {
+    fn new() -> SendQueues {
+        SendQueues {
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+            next_id: 0,
+            handles: 1,
+        }
+    }
+
+    /// Add a queue for a new connection, returning its id.
+    fn register(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queues.insert(id, (VecDeque::new(), true));
+        self.handles += 1;
+        id
+    }
+
+    /// Forget a connection once the datagrams it queued are sent.
+    fn unregister(&mut self, id: usize) {
+        self.handles -= 1;
+        let empty = match self.queues.get_mut(&id) {
+            Some(&mut (ref queue, ref mut alive)) => {
+                *alive = false;
+                queue.is_empty()
+            },
+            None => false,
+        };
+        if empty {
+            self.queues.remove(&id);
+        }
+    }
+
+    /// Return the number of datagrams a connection has waiting.
+    fn len(&self, id: usize) -> usize {
+        self.queues.get(&id).map_or(0, |&(ref queue, _)| queue.len())
+    }
+
+    fn push(&mut self, id: usize, datagram: Datagram) {
+        if let Some(&mut (ref mut queue, _)) = self.queues.get_mut(&id) {
+            if queue.is_empty() {
+                self.turns.push_back(id);
+            }
+            queue.push_back(datagram);
+        }
+    }
+
+    /// Take the next datagram to send, from the connection whose turn it is.
+    fn next(&mut self) -> Option<Datagram> {
+        let id = match self.turns.pop_front() {
+            Some(id) => id,
+            None => return None,
+        };
+
+        let (datagram, more, alive) = match self.queues.get_mut(&id) {
+            Some(&mut (ref mut queue, alive)) => (queue.pop_front(), !queue.is_empty(), alive),
+            None => (None, false, false),
+        };
+        if more {
+            self.turns.push_back(id);
+        } else if !alive {
+            self.queues.remove(&id);
+        }
+        datagram
+    }
+}
+
+/// Send queues shared by the listener, its accepted sockets and the sending
+/// thread.
+struct SendScheduler {
+    queues: Mutex<SendQueues>,
+    /// Signalled when datagrams are queued or sent, and when a handle goes
+    changed: Condvar,
+}
+
+/// Send the datagrams queued by the accepted sockets, until none is left and
+/// nobody can queue more.
+fn send_queued(mut socket: UdpSocket, scheduler: Arc<SendScheduler>) {
+    loop {
+        let (datagram, dst) = {
+            let mut queues = scheduler.queues.lock().unwrap();
+            let mut next = queues.next();
+            while next.is_none() {
+                if queues.handles == 0 {
+                    return;
+                }
+                queues = scheduler.changed.wait(queues).unwrap();
+                next = queues.next();
+            }
+            next.unwrap()
+        };
+
+        // Make room for senders waiting on a full queue
+        scheduler.changed.notify_all();
+        if let Err(e) = socket.send_to(&datagram[..], dst) {
+            debug!("failed to send to {}: {}", dst, e);
+        }
+    }
+}
+
+/// Transport of a socket accepted or connected by a listener: datagrams are
+/// sent through the listener's send queues, and received from the
+/// dispatching thread.
+///
+/// Sending only queues datagrams, so errors reported by the operating system
+/// (e.g., ICMP port unreachable) don't reach the socket.
+struct ListenerTransport {
+    /// Handle of the listening socket, for its local address
+    socket: UdpSocket,
+    scheduler: Arc<SendScheduler>,
+    /// Id of the connection's send queue
+    id: usize,
+    incoming: Receiver<Datagram>,
+    /// Datagram taken from `incoming` by `poll`, not yet received
+    polled: Option<Datagram>,
+    timeout: Option<u64>,
+    failure: Failure,
+    /// Tells the dispatching thread to stop routing datagrams here
+    dropped: Arc<AtomicBool>,
+    /// For outgoing connections, the routes to register the connection in
+    /// once it sends its SYN, and the sending end of `incoming`
+    outgoing: Option<(Arc<Mutex<Routes>>, SyncSender<Datagram>)>,
+}
+
+impl DatagramTransport for ListenerTransport {
+    fn send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<()> {
+        // The remote peer replies to an outgoing connection with the connection
+        // id of its SYN
+        let syn = match PacketRef::new(buf) {
+            Ok(packet) if packet.get_type() == PacketType::Syn => Some(packet.connection_id()),
+            _ => None,
+        };
+        if let Some(connection_id) = syn {
+            if let Some((routes, tx)) = self.outgoing.take() {
+                let mut routes = routes.lock().unwrap();
+                if routes.contains_key(&(dst, connection_id)) {
+                    return Err(IoError {
+                        kind: ConnectionFailed,
+                        desc: "Connection id already in use",
+                        detail: Some(format!("connection id {} to {}", connection_id, dst)),
+                    });
+                }
+                routes.insert((dst, connection_id), (tx, self.dropped.clone()));
+            }
+        }
+
+        queue_datagram(&self.scheduler, self.id, (buf.to_vec(), dst));
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        let (datagram, src) = match self.polled.take() {
+            Some(datagram) => datagram,
+            None => {
+                let timeout = self.timeout;
+                try!(self.next_datagram(timeout))
+            },
+        };
+        Ok((buf.clone_from_slice(&datagram[..]), src))
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout = timeout_ms;
+    }
+
+    fn poll(&mut self, timeout_ms: Option<u64>) -> IoResult<bool> {
+        if self.polled.is_some() {
+            return Ok(true);
+        }
+        match self.next_datagram(timeout_ms) {
+            Ok(datagram) => {
+                self.polled = Some(datagram);
+                Ok(true)
+            },
+            Err(ref e) if e.kind == TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+
+    fn try_clone(&self) -> Option<Box<DatagramTransport + Send>> {
+        self.scheduler.queues.lock().unwrap().handles += 1;
+        Some(Box::new(ListenerSender {
+            socket: self.socket.clone(),
+            scheduler: self.scheduler.clone(),
+            id: self.id,
+            dropped: self.dropped.clone(),
+        }))
+    }
+}
+
+impl ListenerTransport {
+    /// Wait up to `timeout_ms` milliseconds, or forever if `None`, for the
+    /// next datagram routed to this transport.
+    fn next_datagram(&mut self, timeout_ms: Option<u64>) -> IoResult<Datagram> {
+        let deadline = timeout_ms.map(|ms| SteadyTime::now() + Duration::milliseconds(ms as i64));
+        loop {
+            let received = match deadline {
+                None => self.incoming.recv().map_err(|_| TryRecvError::Disconnected),
+                Some(_) => self.incoming.try_recv(),
+            };
+            match received {
+                Ok(datagram) => return Ok(datagram),
+                Err(TryRecvError::Disconnected) => return Err(listener_error(&self.failure)),
+                Err(TryRecvError::Empty) => {},
+            }
+
+            // Channels can't wait with a timeout, so poll them instead
+            if deadline.map_or(false, |deadline| SteadyTime::now() >= deadline) {
+                return Err(IoError {
+                    kind: TimedOut,
+                    desc: "Timed out",
+                    detail: None,
+                });
+            }
+            timer::sleep(Duration::milliseconds(TIMER_GRANULARITY as i64));
+        }
+    }
+}
+
+impl Drop for ListenerTransport {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::SeqCst);
+        self.scheduler.queues.lock().unwrap().unregister(self.id);
+        self.scheduler.changed.notify_all();
+    }
+}
+
+/// Clone of a `ListenerTransport`, sending through the same send queue.
+///
+/// Datagrams are only routed to the transport it was cloned from, so it
+/// can't receive, and stops sending once that transport is dropped.
+struct ListenerSender {
+    /// Handle of the listening socket, for its local address
+    socket: UdpSocket,
+    scheduler: Arc<SendScheduler>,
+    /// Id of the connection's send queue
+    id: usize,
+    /// Whether the transport it was cloned from was dropped
+    dropped: Arc<AtomicBool>,
+}
+
+impl DatagramTransport for ListenerSender {
+    fn send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<()> {
+        if self.dropped.load(Ordering::SeqCst) {
+            return Err(IoError {
+                kind: Closed,
+                desc: "Transport closed",
+                detail: None,
+            });
+        }
+        queue_datagram(&self.scheduler, self.id, (buf.to_vec(), dst));
+        Ok(())
+    }
+
+    fn recv_from(&mut self, _buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        Err(send_only_error())
+    }
+
+    fn set_read_timeout(&mut self, _timeout_ms: Option<u64>) {}
+
+    fn poll(&mut self, _timeout_ms: Option<u64>) -> IoResult<bool> {
+        Err(send_only_error())
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+}
+
+impl Drop for ListenerSender {
+    fn drop(&mut self) {
+        self.scheduler.queues.lock().unwrap().handles -= 1;
+        self.scheduler.changed.notify_all();
+    }
+}
+
+/// Queue `datagram` for sending on the send queue `id`, waiting while the
+/// queue is full.
+fn queue_datagram(scheduler: &SendScheduler, id: usize, datagram: Datagram) {
+    let mut queues = scheduler.queues.lock().unwrap();
+    while queues.len(id) >= MAX_QUEUED {
+        queues = scheduler.changed.wait(queues).unwrap();
+    }
+    queues.push(id, datagram);
+    scheduler.changed.notify_all();
+}
+
+fn send_only_error() -> IoError {
+    IoError {
+        kind: IoUnavailable,
+        desc: "Only the transport this one was cloned from can receive",
+        detail: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UtpListener, SendQueues};
+    use std::old_io::EndOfFile;
+    use std::old_io::net::ip::{SocketAddr, Ipv4Addr};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use socket::UtpSocket;
+
+    fn loopback() -> SocketAddr {
+        SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }
+    }
+
+    #[test]
+    fn test_connections_take_turns_sending() {
+        let mut queues = SendQueues::new();
+        let (bulk, interactive) = (queues.register(), queues.register());
+        let dst = loopback();
+
+        for i in 0..5 {
+            queues.push(bulk, (vec!(0, i), dst));
+        }
+        queues.push(interactive, (vec!(1, 0), dst));
+        queues.push(interactive, (vec!(1, 1), dst));
+
+        let mut sent = Vec::new();
+        while let Some((datagram, _)) = queues.next() {
+            sent.push(datagram);
+        }
+        assert_eq!(sent, vec!(vec!(0, 0), vec!(1, 0), vec!(0, 1), vec!(1, 1),
+                              vec!(0, 2), vec!(0, 3), vec!(0, 4)));
+
+        // Queued datagrams outlive their socket
+        queues.push(bulk, (vec!(0, 5), dst));
+        queues.unregister(bulk);
+        queues.unregister(interactive);
+        assert_eq!(queues.queues.len(), 1);
+        assert_eq!(queues.next(), Some((vec!(0, 5), dst)));
+        assert!(queues.queues.is_empty());
+        assert_eq!(queues.handles, 1);
+    }
+
+    #[test]
+    fn test_try_connect_error() {
+        use std::old_io::net::udp::UdpSocket;
+        use packet::{Packet, PacketBuilder, PacketType};
+        use error::UtpError;
+
+        let mut listener = UtpListener::bind(loopback()).unwrap();
+        let mut peer = UdpSocket::bind(loopback()).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+
+        thread::spawn(move || {
+            // Reject the connection
+            let mut buf = [0; 1500];
+            let (read, src) = peer.recv_from(&mut buf).unwrap();
+            let syn = Packet::decode(&buf[..read]).unwrap();
+            let reply = PacketBuilder::fin()
+                .connection_id(syn.connection_id())
+                .seq(1)
+                .ack(syn.seq_nr())
+                .build();
+            peer.send_to(&reply.bytes()[..], src).unwrap();
+        });
+
+        let error = match listener.try_connect(peer_addr) {
+            Ok(_) => panic!("expected the handshake to fail"),
+            Err(e) => e,
+        };
+        match error.utp_error() {
+            Some(UtpError::HandshakeFailed { peer, packet_type: PacketType::Fin, .. }) => {
+                assert_eq!(peer, peer_addr);
+            }
+            e => panic!("expected a failed handshake, got {:?}", e),
+        }
+        assert!(error.into_socket().is_some());
+    }
+
+    #[test]
+    fn test_accept_many_connections() {
+        const CLIENTS: u8 = 5;
+        let mut listener = UtpListener::bind(loopback()).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        for i in 0..CLIENTS {
+            thread::spawn(move || {
+                let client = UtpSocket::bind(loopback()).unwrap();
+                let mut client = client.connect(server_addr).unwrap();
+                client.send_to(&[i; 3000]).unwrap();
+                client.close().unwrap();
+            });
+        }
+
+        // Serve every connection at once
+        let (tx, rx) = channel();
+        for _ in 0..CLIENTS {
+            let (mut socket, src) = listener.accept().unwrap();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut buf = [0; 1500];
+                let mut received = Vec::new();
+                loop {
+                    match socket.recv_from(&mut buf) {
+                        Ok((read, from)) => {
+                            assert_eq!(from, src);
+                            received.push_all(&buf[..read]);
+                        },
+                        Err(ref e) if e.kind == EndOfFile => break,
+                        Err(e) => panic!("{:?}", e),
+                    }
+                }
+                tx.send(received).unwrap();
+            });
+        }
+
+        let mut senders: Vec<u8> = (0..CLIENTS).map(|_| {
+            let received = rx.recv().unwrap();
+            assert_eq!(received.len(), 3000);
+            assert!(received.iter().all(|&b| b == received[0]));
+            received[0]
+        }).collect();
+        senders.sort();
+        assert_eq!(senders, (0..CLIENTS).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_accept_and_connect_on_one_port() {
+        let mut listener = UtpListener::bind(loopback()).unwrap();
+        let mut peer = UtpListener::bind(loopback()).unwrap();
+        let (addr, peer_addr) = (listener.local_addr().unwrap(), peer.local_addr().unwrap());
+
+        // The peer connects back to us once we connected to it
+        thread::spawn(move || {
+            let (mut socket, src) = peer.accept().unwrap();
+            assert_eq!(src, addr);
+            let mut buf = [0; 1500];
+            let (read, _) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..read], &b"ping"[..]);
+
+            let mut back = peer.connect(addr).unwrap();
+            back.send_to(b"pong").unwrap();
+            back.close().unwrap();
+            socket.close().unwrap();
+        });
+
+        let mut outgoing = listener.connect(peer_addr).unwrap();
+        outgoing.send_to(b"ping").unwrap();
+
+        let (mut incoming, src) = listener.accept().unwrap();
+        assert_eq!(src, peer_addr);
+        let mut buf = [0; 1500];
+        let (read, _) = incoming.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], &b"pong"[..]);
+
+        // Both connections share the port until closed
+        match outgoing.recv_from(&mut buf) {
+            Err(ref e) if e.kind == EndOfFile => {},
+            r => panic!("expected end of file, got {:?}", r),
+        }
+        outgoing.close().unwrap();
+    }
+
+    #[test]
+    fn test_accept_backlog_is_bounded() {
+        use super::MAX_BACKLOG;
+        use packet::PacketBuilder;
+        use std::old_io::net::udp::UdpSocket;
+        use std::old_io::timer;
+        use std::time::Duration;
+
+        let mut listener = UtpListener::bind(loopback()).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // A flood of SYNs fills the backlog; the rest is dropped
+        let mut flood = UdpSocket::bind(loopback()).unwrap();
+        for id in 0..MAX_BACKLOG as u16 + 10 {
+            let syn = PacketBuilder::syn().connection_id(id * 2).build();
+            flood.send_to(&syn.bytes()[..], server_addr).unwrap();
+        }
+        timer::sleep(Duration::milliseconds(200));
+        for _ in 0..MAX_BACKLOG {
+            listener.accept().unwrap();
+        }
+
+        // Room was made for new requests
+        let mut late = UdpSocket::bind(loopback()).unwrap();
+        let late_addr = late.socket_name().unwrap();
+        let syn = PacketBuilder::syn().connection_id(1).build();
+        late.send_to(&syn.bytes()[..], server_addr).unwrap();
+        let (_socket, src) = listener.accept().unwrap();
+        assert_eq!(src, late_addr);
+    }
+
+    #[test]
+    fn test_accepted_socket_cannot_rebind() {
+        use std::old_io::IoUnavailable;
+
+        let mut listener = UtpListener::bind(loopback()).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let client = UtpSocket::bind(loopback()).unwrap();
+            let mut client = client.connect(server_addr).unwrap();
+            client.close().unwrap();
+        });
+
+        // The listener's port is shared with its other connections
+        let (mut socket, _src) = listener.accept().unwrap();
+        match socket.rebind(loopback()) {
+            Err(ref e) if e.kind == IoUnavailable => {},
+            r => panic!("expected rebinding to be unavailable, got {:?}", r),
+        }
+        socket.close().unwrap();
+    }
+
+    #[test]
+    fn test_accepted_socket_readiness() {
+        use std::sync::mpsc::channel;
+
+        let mut listener = UtpListener::bind(loopback()).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let client = UtpSocket::bind(loopback()).unwrap();
+            let mut client = client.connect(server_addr).unwrap();
+            rx.recv().unwrap();
+            client.send(&[1, 2, 3]).unwrap();
+            client.close().unwrap();
+        });
+
+        // Without a descriptor of its own, the socket reports readiness itself
+        let (mut socket, _src) = listener.accept().unwrap();
+        if cfg!(unix) {
+            assert!(socket.as_raw_fd().is_none());
+        }
+        assert!(!socket.wait_readable(Some(0)).unwrap());
+
+        tx.send(()).unwrap();
+        while !socket.has_buffered_data() {
+            assert!(socket.wait_readable(None).unwrap());
+            socket.process_incoming().unwrap();
+        }
+        let mut buf = [0u8; 16];
+        let (read, _src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], &[1, 2, 3][..]);
+    }
+
+    #[test]
+    fn test_accepted_socket_keep_alive_handle() {
+        use std::old_io::timer;
+        use std::time::Duration;
+
+        let mut listener = UtpListener::bind(loopback()).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let client = UtpSocket::bind(loopback()).unwrap();
+            let mut client = client.connect(server_addr).unwrap();
+            tx.send(()).unwrap();
+
+            // The keep-alive comes through the listener's port
+            let last_received = client.last_received_at();
+            while client.last_received_at() == last_received {
+                timer::sleep(Duration::milliseconds(10));
+                client.process_incoming().unwrap();
+            }
+            tx.send(()).unwrap();
+        });
+
+        let (mut socket, _src) = listener.accept().unwrap();
+        rx.recv().unwrap();
+        let mut handle = socket.keep_alive_handle().unwrap();
+        thread::spawn(move || handle.send_keep_alive().unwrap());
+        rx.recv().unwrap();
+    }
+}
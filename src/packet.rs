@@ -1,8 +1,13 @@
 use std::mem::transmute;
 use std::fmt;
+use std::slice;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 use std::iter::range_inclusive;
 use std::num::Int;
 use bit_iterator::BitIterator;
+use util::now_microseconds;
+use config::ParsingMode;
 
 pub const HEADER_SIZE: usize = 20;
 
@@ -16,18 +21,97 @@ macro_rules! u8_to_unsigned_be {
     })
 }
 
-#[derive(PartialEq,Eq,Debug)]
+/// Type of a uTP packet.
+#[derive(PartialEq,Eq,Debug,Clone,Copy)]
+#[cfg_attr(feature = "serde_serialization", derive(Serialize))]
 pub enum PacketType {
+    /// Regular data packet.
     Data  = 0,
+    /// Finalize the connection.
     Fin   = 1,
+    /// State packet, used to acknowledge received data.
     State = 2,
+    /// Forcibly terminate the connection.
     Reset = 3,
+    /// Initiate a connection.
     Syn   = 4,
 }
 
+/// Type of a packet extension.
 #[derive(PartialEq,Eq,Debug,Clone,Copy)]
+#[cfg_attr(feature = "serde_serialization", derive(Serialize))]
 pub enum ExtensionType {
-    SelectiveAck = 1,
+    /// Selective acknowledgment extension.
+    SelectiveAck,
+    /// Feature flags exchanged during the handshake.
+    ///
+    /// Its wire value differs from libutp's extension bits (2), which libutp
+    /// sends as eight zero bytes and would otherwise read as no features at
+    /// all, not even selective acknowledgements.
+    Features,
+    /// Identifies the group of data packets protected by a parity packet.
+    Parity,
+    /// End-to-end digest of the byte stream, carried by FIN packets.
+    Digest,
+    /// Extension not known to this implementation, with its wire value.
+    Unknown(u8),
+}
+
+impl ExtensionType {
+    /// Return the extension type corresponding to the given wire value.
+    pub fn from_u8(byte: u8) -> ExtensionType {
+        match byte {
+            1 => ExtensionType::SelectiveAck,
+            4 => ExtensionType::Parity,
+            8 => ExtensionType::Digest,
+            16 => ExtensionType::Features,
+            n => ExtensionType::Unknown(n),
+        }
+    }
+
+    /// Return the wire value of this extension type.
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            ExtensionType::SelectiveAck => 1,
+            ExtensionType::Features => 16,
+            ExtensionType::Parity => 4,
+            ExtensionType::Digest => 8,
+            ExtensionType::Unknown(n) => n,
+        }
+    }
+}
+
+/// Errors raised when decoding a malformed packet.
+#[derive(PartialEq,Eq,Debug,Clone,Copy)]
+pub enum ParseError {
+    /// The buffer is too short to hold a packet header.
+    InvalidPacketLength,
+    /// An extension's stated length exceeds the remaining buffer, or doesn't
+    /// suit the extension's type.
+    InvalidExtensionLength,
+    /// The packet type is unknown.
+    InvalidPacketType,
+    /// The protocol version isn't supported.
+    InvalidVersion,
+    /// A packet other than DATA carries a payload.
+    UnexpectedPayload,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::InvalidPacketLength =>
+                write!(f, "Packet is shorter than the header size"),
+            ParseError::InvalidExtensionLength =>
+                write!(f, "Extension length exceeds the packet size"),
+            ParseError::InvalidPacketType =>
+                write!(f, "Unknown packet type"),
+            ParseError::InvalidVersion =>
+                write!(f, "Unsupported protocol version"),
+            ParseError::UnexpectedPayload =>
+                write!(f, "Non-data packet carries a payload"),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -130,10 +214,59 @@ impl fmt::Debug for PacketHeader {
     }
 }
 
+/// Iterator over the extensions of a packet, yielding each extension's type
+/// and raw payload.
+pub struct ExtensionIterator<'a> {
+    inner: slice::Iter<'a, Extension>,
+}
+
+impl<'a> Iterator for ExtensionIterator<'a> {
+    type Item = (ExtensionType, &'a [u8]);
+
+    fn next(&mut self) -> Option<(ExtensionType, &'a [u8])> {
+        self.inner.next().map(|extension| (extension.ty, &extension.data[..]))
+    }
+}
+
+/// Data carried by a packet.
+///
+/// Cloning a payload shares its bytes rather than copying them, so that the
+/// packets queued on several connections for the same data (see
+/// `UtpSocket::broadcast`) take up memory only once. The bytes are copied
+/// when a packet sharing them is modified.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Payload(Arc<Vec<u8>>);
+
+impl Payload {
+    /// Wrap data carried by a single packet.
+    pub fn new(data: Vec<u8>) -> Payload {
+        Payload(Arc::new(data))
+    }
+
+    /// Wrap data shared with other packets.
+    pub fn shared(data: Arc<Vec<u8>>) -> Payload {
+        Payload(data)
+    }
+}
+
+impl Deref for Payload {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &*self.0
+    }
+}
+
+impl DerefMut for Payload {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        self.0.make_unique()
+    }
+}
+
 pub struct Packet {
     header: PacketHeader,
     pub extensions: Vec<Extension>,
-    pub payload: Vec<u8>,
+    pub payload: Payload,
 }
 
 impl Packet {
@@ -151,7 +284,7 @@ impl Packet {
                 ack_nr: 0,
             },
             extensions: Vec::new(),
-            payload: Vec::new(),
+            payload: Payload::new(Vec::new()),
         }
     }
 
@@ -250,11 +383,19 @@ impl Packet {
                     data: bv,
                 };
                 self.extensions.push(extension);
-                self.header.extension |= ExtensionType::SelectiveAck as u8;
+                self.header.extension |= ExtensionType::SelectiveAck.to_u8();
             }
         }
     }
 
+    /// Append an extension of the given type to the packet.
+    pub fn add_extension(&mut self, ty: ExtensionType, data: Vec<u8>) {
+        if self.extensions.is_empty() {
+            self.header.extension = ty.to_u8();
+        }
+        self.extensions.push(Extension { ty: ty, data: data });
+    }
+
     pub fn bytes(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(self.len());
         buf.push_all(self.header.bytes());
@@ -264,7 +405,7 @@ impl Packet {
             // next extension id
             match extensions.peek() {
                 None => buf.push(0u8),
-                Some(next) => buf.push(next.ty as u8),
+                Some(next) => buf.push(next.ty.to_u8()),
             }
             buf.push_all(&extension.to_bytes()[..]);
         }
@@ -278,57 +419,345 @@ impl Packet {
         self.header.len() + self.payload.len() + ext_len
     }
 
+    /// Return an iterator over the packet's extensions, known or otherwise.
+    pub fn extensions(&self) -> ExtensionIterator {
+        ExtensionIterator { inner: self.extensions.iter() }
+    }
+
     /// Decode a byte slice and construct the equivalent Packet.
     ///
     /// Note that this method makes no attempt to guess the payload size, saving
     /// all except the initial 20 bytes corresponding to the header as payload.
     /// It's the caller's responsability to use an appropriately sized buffer.
-    pub fn decode(buf: &[u8]) -> Packet {
+    ///
+    /// Returns an error if the buffer is shorter than a header, if the packet
+    /// type is unknown or if any extension claims to be longer than the
+    /// remaining buffer.
+    pub fn decode(buf: &[u8]) -> Result<Packet, ParseError> {
+        Packet::decode_with_mode(buf, ParsingMode::Lenient)
+    }
+
+    /// Decode a byte slice like `decode`, validating it according to the
+    /// given parsing mode.
+    ///
+    /// In strict mode, packets with an unsupported version, malformed
+    /// selective ACKs or a payload on anything but a DATA packet are rejected
+    /// as well.
+    pub fn decode_with_mode(buf: &[u8], mode: ParsingMode) -> Result<Packet, ParseError> {
+        try!(PacketRef::new(buf));
+
         let header = PacketHeader::decode(buf);
+        let strict = mode == ParsingMode::Strict;
+
+        if strict && header.get_version() != 1 {
+            return Err(ParseError::InvalidVersion);
+        }
 
         let mut extensions = Vec::new();
         let mut idx = HEADER_SIZE;
         let mut kind = header.extension;
 
-        // Consume known extensions and skip over unknown ones
-        while idx < buf.len() && kind != 0 {
+        // Consume extensions, making sure each one fits in the buffer
+        while kind != 0 {
+            if idx + 2 > buf.len() {
+                return Err(ParseError::InvalidExtensionLength);
+            }
+
             let len = buf[idx + 1] as usize;
             let extension_start = idx + 2;
             let payload_start = extension_start + len;
 
-            if kind == ExtensionType::SelectiveAck as u8 { // or more generally, a known kind
-                let extension = Extension {
-                    ty: ExtensionType::SelectiveAck,
-                    data: buf[extension_start..payload_start].to_vec(),
-                };
-                extensions.push(extension);
+            if payload_start > buf.len() {
+                return Err(ParseError::InvalidExtensionLength);
             }
 
+            let ty = ExtensionType::from_u8(kind);
+            if strict && ty == ExtensionType::SelectiveAck && (len < 4 || len % 4 != 0) {
+                return Err(ParseError::InvalidExtensionLength);
+            }
+
+            let extension = Extension {
+                ty: ty,
+                data: buf[extension_start..payload_start].to_vec(),
+            };
+            extensions.push(extension);
+
             kind = buf[idx];
-            idx += payload_start;
+            idx = payload_start;
         }
 
-        let mut payload;
-        if idx < buf.len() {
-            payload = buf[idx..].to_vec();
-        } else {
-            payload = Vec::new();
+        // Parity packets carry the parity of data payloads as their own
+        let parity = extensions.iter().any(|extension| extension.ty == ExtensionType::Parity);
+        if strict && header.get_type() != PacketType::Data && !parity && idx < buf.len() {
+            return Err(ParseError::UnexpectedPayload);
         }
 
-        Packet {
+        Ok(Packet {
             header: header,
             extensions: extensions,
-            payload: payload,
+            payload: Payload::new(buf[idx..].to_vec()),
+        })
+    }
+
+    /// Return a summary of this packet's contents.
+    pub fn summary(&self) -> PacketSummary {
+        PacketSummary {
+            packet_type: self.get_type(),
+            version: self.header.get_version(),
+            connection_id: self.connection_id(),
+            seq_nr: self.seq_nr(),
+            ack_nr: self.ack_nr(),
+            wnd_size: self.wnd_size(),
+            timestamp_microseconds: self.timestamp_microseconds(),
+            timestamp_difference_microseconds: self.timestamp_difference_microseconds(),
+            extensions: self.extensions().map(|(ty, _)| ty).collect(),
+            payload_len: self.payload.len(),
         }
     }
+}
 
-    /// Return a clone of this object without the payload
-    pub fn shallow_clone(&self) -> Packet {
-        Packet {
-            header: self.header.clone(),
-            extensions: self.extensions.clone(),
-            payload: Vec::new(),
+/// Read-only view of a packet's header fields, parsed directly from a borrowed
+/// datagram.
+///
+/// Nothing is copied, so this is cheap enough to inspect every incoming
+/// datagram (e.g., to dispatch on the connection id or drop implausible
+/// packets) before deciding whether to decode it into a `Packet`.
+#[derive(Clone,Copy)]
+pub struct PacketRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> PacketRef<'a> {
+    /// Wrap a datagram, checking that it holds a complete header of a known
+    /// packet type. Extensions and payload aren't validated.
+    pub fn new(buf: &'a [u8]) -> Result<PacketRef<'a>, ParseError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(ParseError::InvalidPacketLength);
+        }
+
+        if buf[0] >> 4 > PacketType::Syn as u8 {
+            return Err(ParseError::InvalidPacketType);
         }
+
+        Ok(PacketRef { buf: buf })
+    }
+
+    pub fn get_type(&self) -> PacketType {
+        unsafe { transmute(self.buf[0] >> 4) }
+    }
+
+    pub fn get_version(&self) -> u8 {
+        self.buf[0] & 0x0F
+    }
+
+    pub fn connection_id(&self) -> u16 {
+        let buf = self.buf;
+        u8_to_unsigned_be!(buf, 2, 3, u16)
+    }
+
+    pub fn timestamp_microseconds(&self) -> u32 {
+        let buf = self.buf;
+        u8_to_unsigned_be!(buf, 4, 7, u32)
+    }
+
+    pub fn timestamp_difference_microseconds(&self) -> u32 {
+        let buf = self.buf;
+        u8_to_unsigned_be!(buf, 8, 11, u32)
+    }
+
+    pub fn wnd_size(&self) -> u32 {
+        let buf = self.buf;
+        u8_to_unsigned_be!(buf, 12, 15, u32)
+    }
+
+    pub fn seq_nr(&self) -> u16 {
+        let buf = self.buf;
+        u8_to_unsigned_be!(buf, 16, 17, u16)
+    }
+
+    pub fn ack_nr(&self) -> u16 {
+        let buf = self.buf;
+        u8_to_unsigned_be!(buf, 18, 19, u16)
+    }
+
+    /// Decode the whole packet, taking ownership of its contents.
+    pub fn to_packet(&self, mode: ParsingMode) -> Result<Packet, ParseError> {
+        Packet::decode_with_mode(self.buf, mode)
+    }
+}
+
+/// Summary of a packet's header fields, extensions and payload size.
+///
+/// Intended for logging and analysis tools. When the `serde_serialization`
+/// feature is enabled it can be serialized (e.g., to JSON) with serde.
+#[derive(PartialEq,Eq,Debug,Clone)]
+#[cfg_attr(feature = "serde_serialization", derive(Serialize))]
+pub struct PacketSummary {
+    /// Packet type
+    pub packet_type: PacketType,
+    /// Protocol version
+    pub version: u8,
+    /// Connection identifier
+    pub connection_id: u16,
+    /// Sequence number
+    pub seq_nr: u16,
+    /// Acknowledgment number
+    pub ack_nr: u16,
+    /// Advertised receive window, in bytes
+    pub wnd_size: u32,
+    /// Timestamp at which the packet was sent, in microseconds
+    pub timestamp_microseconds: u32,
+    /// Timestamp difference reported by the sender, in microseconds
+    pub timestamp_difference_microseconds: u32,
+    /// Types of the packet's extensions, in wire order
+    pub extensions: Vec<ExtensionType>,
+    /// Payload length, in bytes
+    pub payload_len: usize,
+}
+
+impl PacketSummary {
+    /// Decode a raw datagram and summarize the packet it holds.
+    pub fn from_bytes(buf: &[u8]) -> Result<PacketSummary, ParseError> {
+        Packet::decode(buf).map(|packet| packet.summary())
+    }
+}
+
+/// Fluent builder for packets.
+///
+/// Only DATA packets carry a payload, and the packet timestamp is filled in
+/// when the packet is built. Fields that are not set, including the
+/// connection id, default to zero.
+pub struct PacketBuilder {
+    ty: PacketType,
+    connection_id: u16,
+    seq_nr: u16,
+    ack_nr: u16,
+    wnd_size: u32,
+    reply_to: Option<u32>,
+    sack: Option<Vec<u8>>,
+    extensions: Vec<(ExtensionType, Vec<u8>)>,
+    payload: Payload,
+}
+
+impl PacketBuilder {
+    fn new(ty: PacketType) -> PacketBuilder {
+        PacketBuilder {
+            ty: ty,
+            connection_id: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            wnd_size: 0,
+            reply_to: None,
+            sack: None,
+            extensions: Vec::new(),
+            payload: Payload::new(Vec::new()),
+        }
+    }
+
+    /// Start building a DATA packet carrying the given payload.
+    pub fn data(payload: Vec<u8>) -> PacketBuilder {
+        let mut builder = PacketBuilder::new(PacketType::Data);
+        builder.payload = Payload::new(payload);
+        builder
+    }
+
+    /// Start building a DATA packet carrying a payload shared with other
+    /// packets.
+    pub fn shared_data(payload: Arc<Vec<u8>>) -> PacketBuilder {
+        let mut builder = PacketBuilder::new(PacketType::Data);
+        builder.payload = Payload::shared(payload);
+        builder
+    }
+
+    /// Start building a SYN packet.
+    pub fn syn() -> PacketBuilder {
+        PacketBuilder::new(PacketType::Syn)
+    }
+
+    /// Start building a STATE packet.
+    pub fn state() -> PacketBuilder {
+        PacketBuilder::new(PacketType::State)
+    }
+
+    /// Start building a FIN packet.
+    pub fn fin() -> PacketBuilder {
+        PacketBuilder::new(PacketType::Fin)
+    }
+
+    /// Start building a RESET packet.
+    pub fn reset() -> PacketBuilder {
+        PacketBuilder::new(PacketType::Reset)
+    }
+
+    /// Start building a packet of the given type with no payload.
+    pub fn with_type(ty: PacketType) -> PacketBuilder {
+        PacketBuilder::new(ty)
+    }
+
+    pub fn connection_id(mut self, connection_id: u16) -> PacketBuilder {
+        self.connection_id = connection_id;
+        self
+    }
+
+    pub fn seq(mut self, seq_nr: u16) -> PacketBuilder {
+        self.seq_nr = seq_nr;
+        self
+    }
+
+    pub fn ack(mut self, ack_nr: u16) -> PacketBuilder {
+        self.ack_nr = ack_nr;
+        self
+    }
+
+    pub fn wnd_size(mut self, wnd_size: u32) -> PacketBuilder {
+        self.wnd_size = wnd_size;
+        self
+    }
+
+    /// Compute the timestamp difference field relative to the given remote
+    /// timestamp when the packet is built.
+    pub fn in_reply_to(mut self, timestamp: u32) -> PacketBuilder {
+        self.reply_to = Some(timestamp);
+        self
+    }
+
+    /// Attach a Selective ACK extension with the given bitmask.
+    pub fn sack(mut self, sack: Vec<u8>) -> PacketBuilder {
+        self.sack = Some(sack);
+        self
+    }
+
+    /// Attach an extension of the given type and contents.
+    pub fn extension(mut self, ty: ExtensionType, data: Vec<u8>) -> PacketBuilder {
+        self.extensions.push((ty, data));
+        self
+    }
+
+    /// Build the packet, stamping it with the current time.
+    pub fn build(self) -> Packet {
+        let mut packet = Packet::new();
+        packet.set_type(self.ty);
+        packet.set_connection_id(self.connection_id);
+        packet.set_seq_nr(self.seq_nr);
+        packet.set_ack_nr(self.ack_nr);
+        packet.set_wnd_size(self.wnd_size);
+        packet.payload = self.payload;
+        if self.sack.is_some() {
+            packet.set_sack(self.sack);
+        }
+        for (ty, data) in self.extensions.into_iter() {
+            packet.add_extension(ty, data);
+        }
+
+        let now = now_microseconds();
+        packet.set_timestamp_microseconds(now);
+        if let Some(timestamp) = self.reply_to {
+            // The remote peer's clock has its own epoch, so its timestamp may
+            // well be ahead of ours
+            packet.set_timestamp_difference_microseconds(now.wrapping_sub(timestamp));
+        }
+
+        packet
     }
 }
 
@@ -350,9 +779,9 @@ impl fmt::Debug for Packet {
 
 #[cfg(test)]
 mod test {
-    use super::Packet;
-    use super::PacketType::{State, Data};
-    use super::ExtensionType;
+    use super::{Packet, PacketBuilder, PacketSummary, PacketRef, Payload};
+    use super::PacketType::{State, Data, Syn, Reset};
+    use super::{ExtensionType, ParseError};
     use super::HEADER_SIZE;
     use std::num::Int;
 
@@ -360,7 +789,7 @@ mod test {
     fn test_packet_decode() {
         let buf = [0x21, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
                    0x26, 0x21, 0x00, 0x10, 0x00, 0x00, 0x3a, 0xf2, 0x6c, 0x79];
-        let pkt = Packet::decode(&buf);
+        let pkt = Packet::decode(&buf).unwrap();
         assert_eq!(pkt.header.get_version(), 1);
         assert_eq!(pkt.header.get_type(), State);
         assert_eq!(pkt.header.extension, 0);
@@ -379,7 +808,7 @@ mod test {
         let buf = [0x21, 0x01, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                    0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
                    0x00, 0x04, 0x00, 0x00, 0x00, 0x00];
-        let packet = Packet::decode(&buf);
+        let packet = Packet::decode(&buf).unwrap();
         assert_eq!(packet.header.get_version(), 1);
         assert_eq!(packet.header.get_type(), State);
         assert_eq!(packet.header.extension, 1);
@@ -404,7 +833,7 @@ mod test {
                    0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
                    0xff, 0x04, 0x00, 0x00, 0x00, 0x00, // Imaginary extension
                    0x00, 0x04, 0x00, 0x00, 0x00, 0x00];
-        let packet = Packet::decode(&buf);
+        let packet = Packet::decode(&buf).unwrap();
         assert_eq!(packet.header.get_version(), 1);
         assert_eq!(packet.header.get_type(), State);
         assert_eq!(packet.header.extension, 1);
@@ -415,11 +844,45 @@ mod test {
         assert_eq!(packet.seq_nr(), 43859);
         assert_eq!(packet.ack_nr(), 15093);
         assert!(packet.payload.is_empty());
-        assert!(packet.extensions.len() == 1);
+        assert!(packet.extensions.len() == 2);
         assert!(packet.extensions[0].ty == ExtensionType::SelectiveAck);
         assert!(packet.extensions[0].data == vec!(0,0,0,0));
         assert!(packet.extensions[0].len() == 1 + packet.extensions[0].data.len());
         assert!(packet.extensions[0].len() == 5);
+        assert!(packet.extensions[1].ty == ExtensionType::Unknown(0xff));
+        assert_eq!(packet.len(), buf.len());
+    }
+
+    #[test]
+    fn test_extension_iterator() {
+        let buf = [0x21, 0x01, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
+                   0xff, 0x04, 0x01, 0x00, 0x00, 0x00,
+                   0x00, 0x02, 0xca, 0xfe];
+        let packet = Packet::decode(&buf).unwrap();
+        let extensions: Vec<(ExtensionType, &[u8])> = packet.extensions().collect();
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions[0], (ExtensionType::SelectiveAck, &[1, 0, 0, 0][..]));
+        assert_eq!(extensions[1], (ExtensionType::Unknown(0xff), &[0xca, 0xfe][..]));
+        assert_eq!(&packet.bytes()[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_decode_truncated_extension() {
+        // Extension claims 16 bytes but only 4 follow
+        let buf = [0x21, 0x01, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
+                   0x00, 0x10, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(Packet::decode(&buf).err(), Some(ParseError::InvalidExtensionLength));
+
+        // Extension header itself is missing
+        assert_eq!(Packet::decode(&buf[..21]).err(), Some(ParseError::InvalidExtensionLength));
+    }
+
+    #[test]
+    fn test_decode_short_packet() {
+        let buf = [0x21, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a];
+        assert_eq!(Packet::decode(&buf).err(), Some(ParseError::InvalidPacketLength));
     }
 
     #[test]
@@ -436,7 +899,7 @@ mod test {
         pkt.header.seq_nr = seq_nr.to_be();
         pkt.header.ack_nr = ack_nr.to_be();
         pkt.header.wnd_size = window_size.to_be();
-        pkt.payload = payload.clone();
+        pkt.payload = Payload::new(payload.clone());
         let header = pkt.header;
         let buf = [0x01, 0x00, 0x41, 0xa8, 0x00, 0xe9, 0x03, 0x89,
                    0x65, 0xbf, 0x5d, 0xba, 0x00, 0x10, 0x00, 0x00,
@@ -445,7 +908,7 @@ mod test {
 
         assert_eq!(pkt.len(), buf.len());
         assert_eq!(pkt.len(), HEADER_SIZE + payload.len());
-        assert_eq!(pkt.payload, payload);
+        assert_eq!(&pkt.payload[..], &payload[..]);
         assert_eq!(header.get_version(), 1);
         assert_eq!(header.get_type(), Data);
         assert_eq!(header.extension, 0);
@@ -464,7 +927,173 @@ mod test {
                    0x65, 0xbf, 0x5d, 0xba, 0x00, 0x10, 0x00, 0x00,
                    0x3a, 0xf2, 0x42, 0xc8, 0x48, 0x65, 0x6c, 0x6c,
                    0x6f, 0x0a];
-        assert_eq!(&Packet::decode(&buf).bytes()[..], &buf[..]);
+        assert_eq!(&Packet::decode(&buf).unwrap().bytes()[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_packet_builder() {
+        let payload = vec!(1, 2, 3);
+        let packet = PacketBuilder::data(payload.clone())
+            .connection_id(16808)
+            .seq(15090)
+            .ack(17096)
+            .wnd_size(1500)
+            .build();
+
+        assert_eq!(packet.get_type(), Data);
+        assert_eq!(packet.connection_id(), 16808);
+        assert_eq!(packet.seq_nr(), 15090);
+        assert_eq!(packet.ack_nr(), 17096);
+        assert_eq!(packet.wnd_size(), 1500);
+        assert_eq!(&packet.payload[..], &payload[..]);
+        assert!(packet.timestamp_microseconds() != 0);
+    }
+
+    #[test]
+    fn test_packet_builder_reply_to_later_timestamp() {
+        use util::now_microseconds;
+
+        let timestamp = now_microseconds().wrapping_add(1_000_000);
+        let packet = PacketBuilder::state().in_reply_to(timestamp).build();
+        let difference = packet.timestamp_difference_microseconds();
+        assert_eq!(difference.wrapping_add(timestamp), packet.timestamp_microseconds());
+    }
+
+    #[test]
+    fn test_packet_builder_control_packets() {
+        let packet = PacketBuilder::syn().connection_id(1).seq(1).build();
+        assert_eq!(packet.get_type(), Syn);
+        assert!(packet.payload.is_empty());
+
+        let packet = PacketBuilder::state()
+            .connection_id(1)
+            .sack(vec!(1, 0, 0, 0))
+            .build();
+        assert_eq!(packet.get_type(), State);
+        assert!(packet.payload.is_empty());
+        assert_eq!(packet.extensions.len(), 1);
+        assert_eq!(packet.extensions[0].ty, ExtensionType::SelectiveAck);
+    }
+
+    #[test]
+    fn test_shared_payload() {
+        use std::sync::{arc, Arc};
+        let data = Arc::new(vec!(1, 2, 3));
+        let mut first = PacketBuilder::shared_data(data.clone()).connection_id(1).build();
+        let second = PacketBuilder::shared_data(data.clone()).connection_id(2).build();
+        assert_eq!(arc::strong_count(&data), 3);
+
+        // Modifying one packet leaves the others alone
+        first.payload.push(4);
+        assert_eq!(&first.payload[..], &[1, 2, 3, 4][..]);
+        assert_eq!(&second.payload[..], &[1, 2, 3][..]);
+        assert_eq!(arc::strong_count(&data), 2);
+    }
+
+    #[test]
+    fn test_packet_builder_defaults() {
+        let packet = PacketBuilder::reset().build();
+        assert_eq!(packet.get_type(), Reset);
+        assert_eq!(packet.connection_id(), 0);
+        assert_eq!(packet.seq_nr(), 0);
+        assert_eq!(packet.ack_nr(), 0);
+    }
+
+    #[test]
+    fn test_packet_builder_extensions() {
+        let packet = PacketBuilder::syn()
+            .connection_id(1)
+            .extension(ExtensionType::Features, vec!(1, 0, 0, 0, 0, 0, 4, 0))
+            .extension(ExtensionType::Unknown(0xff), vec!(0xca, 0xfe))
+            .build();
+
+        let decoded = Packet::decode(&packet.bytes()[..]).unwrap();
+        let extensions: Vec<(ExtensionType, &[u8])> = decoded.extensions().collect();
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions[0], (ExtensionType::Features, &[1, 0, 0, 0, 0, 0, 4, 0][..]));
+        assert_eq!(extensions[1], (ExtensionType::Unknown(0xff), &[0xca, 0xfe][..]));
+    }
+
+    #[test]
+    fn test_decode_invalid_type() {
+        let buf = [0x51, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
+                   0x26, 0x21, 0x00, 0x10, 0x00, 0x00, 0x3a, 0xf2, 0x6c, 0x79];
+        assert_eq!(Packet::decode(&buf).err(), Some(ParseError::InvalidPacketType));
+    }
+
+    #[test]
+    fn test_decode_strict_and_lenient() {
+        use config::ParsingMode::{Strict, Lenient};
+
+        // Wrong version nibble
+        let buf = [0x22, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
+                   0x26, 0x21, 0x00, 0x10, 0x00, 0x00, 0x3a, 0xf2, 0x6c, 0x79];
+        assert!(Packet::decode_with_mode(&buf, Lenient).is_ok());
+        assert_eq!(Packet::decode_with_mode(&buf, Strict).err(), Some(ParseError::InvalidVersion));
+
+        // STATE packet with a payload
+        let buf = [0x21, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
+                   0x26, 0x21, 0x00, 0x10, 0x00, 0x00, 0x3a, 0xf2, 0x6c, 0x79,
+                   0x01, 0x02];
+        assert!(Packet::decode_with_mode(&buf, Lenient).is_ok());
+        assert_eq!(Packet::decode_with_mode(&buf, Strict).err(), Some(ParseError::UnexpectedPayload));
+
+        // Selective ACK extension of invalid length
+        let buf = [0x21, 0x01, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
+                   0x26, 0x21, 0x00, 0x10, 0x00, 0x00, 0x3a, 0xf2, 0x6c, 0x79,
+                   0x00, 0x03, 0x01, 0x00, 0x00];
+        assert!(Packet::decode_with_mode(&buf, Lenient).is_ok());
+        assert_eq!(Packet::decode_with_mode(&buf, Strict).err(),
+                   Some(ParseError::InvalidExtensionLength));
     }
 
+    #[test]
+    fn test_packet_ref() {
+        use config::ParsingMode::Strict;
+        let packet = PacketBuilder::data(vec!(1, 2, 3))
+            .connection_id(16808)
+            .seq(15090)
+            .ack(27769)
+            .wnd_size(1048576)
+            .sack(vec!(1, 0, 0, 0))
+            .build();
+        let buf = packet.bytes();
+
+        let view = PacketRef::new(&buf[..]).unwrap();
+        assert_eq!(view.get_type(), Data);
+        assert_eq!(view.get_version(), 1);
+        assert_eq!(view.connection_id(), 16808);
+        assert_eq!(view.seq_nr(), 15090);
+        assert_eq!(view.ack_nr(), 27769);
+        assert_eq!(view.wnd_size(), 1048576);
+        assert_eq!(view.timestamp_microseconds(), packet.timestamp_microseconds());
+        assert_eq!(view.timestamp_difference_microseconds(), 0);
+
+        let decoded = view.to_packet(Strict).unwrap();
+        assert_eq!(decoded.bytes(), buf);
+
+        assert!(PacketRef::new(&buf[..HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn test_packet_summary() {
+        let buf = [0x21, 0x01, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
+                   0xff, 0x04, 0x01, 0x00, 0x00, 0x00,
+                   0x00, 0x02, 0xca, 0xfe,
+                   0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let summary = PacketSummary::from_bytes(&buf).unwrap();
+        assert_eq!(summary.packet_type, State);
+        assert_eq!(summary.version, 1);
+        assert_eq!(summary.connection_id, 16807);
+        assert_eq!(summary.seq_nr, 43859);
+        assert_eq!(summary.ack_nr, 15093);
+        assert_eq!(summary.wnd_size, 1500);
+        assert_eq!(summary.extensions,
+                   vec!(ExtensionType::SelectiveAck, ExtensionType::Unknown(0xff)));
+        assert_eq!(summary.payload_len, 5);
+
+        assert_eq!(PacketSummary::from_bytes(&buf[..10]).err(),
+                   Some(ParseError::InvalidPacketLength));
+    }
 }
@@ -0,0 +1,306 @@
+use std::iter::repeat;
+use std::old_io::{IoResult, IoError, ConnectionFailed, InvalidInput};
+use std::old_io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::old_io::net::tcp::TcpStream;
+use std::old_io::net::udp::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::{AsRawFd, Fd};
+use transport::DatagramTransport;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const UDP_ASSOCIATE: u8 = 3;
+const IPV4: u8 = 1;
+const IPV6: u8 = 4;
+const SUCCEEDED: u8 = 0;
+const RELAY_BUF_SIZE: usize = 2048; // relayed datagram, including the SOCKS header
+
+/// Transport relaying datagrams through a SOCKS5 proxy (RFC 1928), for hosts
+/// that reach the network through one.
+///
+/// The proxy relays datagrams for as long as the transport lives, as closing
+/// the TCP connection to the proxy ends the association. Fragmented
+/// datagrams and relays addressed by domain name aren't supported.
+///
+/// # Examples
+///
+/// ```no_run
+/// use utp::{UtpSocketBuilder, Socks5Transport};
+/// use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+///
+/// let proxy = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 1080 };
+/// let local = SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 };
+/// let transport = Socks5Transport::connect(proxy, local).unwrap();
+/// let socket = UtpSocketBuilder::new().bind_transport(transport).unwrap();
+///
+/// let peer = SocketAddr { ip: Ipv4Addr(198, 51, 100, 7), port: 6881 };
+/// let mut socket = socket.connect(peer).unwrap();
+/// socket.send(b"Hello").unwrap();
+/// ```
+pub struct Socks5Transport {
+    socket: UdpSocket,
+    /// Address of the proxy's UDP relay
+    relay: SocketAddr,
+    /// Connection to the proxy, keeping the association alive
+    _control: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Socks5Transport {
+    /// Bind a UDP socket to `local` and have the SOCKS5 proxy at `proxy` relay
+    /// its datagrams.
+    pub fn connect(proxy: SocketAddr, local: SocketAddr) -> IoResult<Socks5Transport> {
+        Socks5Transport::associate(proxy, local, None)
+    }
+
+    /// Same as `connect`, authenticating to the proxy with a user name and
+    /// password (RFC 1929).
+    pub fn connect_with_credentials(proxy: SocketAddr, local: SocketAddr,
+                                    username: &str, password: &str) -> IoResult<Socks5Transport> {
+        Socks5Transport::associate(proxy, local, Some((username, password)))
+    }
+
+    fn associate(proxy: SocketAddr, local: SocketAddr,
+                 credentials: Option<(&str, &str)>) -> IoResult<Socks5Transport> {
+        let mut socket = try!(UdpSocket::bind(local));
+        let mut control = try!(TcpStream::connect(proxy));
+
+        let method = if credentials.is_some() { USERNAME_PASSWORD } else { NO_AUTHENTICATION };
+        try!(control.write_all(&[VERSION, 1, method]));
+        let reply = try!(control.read_exact(2));
+        if reply[0] != VERSION || reply[1] != method {
+            return Err(proxy_error("The proxy refused the authentication method", None));
+        }
+
+        if let Some((username, password)) = credentials {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "Proxy credentials too long",
+                    detail: None,
+                });
+            }
+            let mut request = vec!(1, username.len() as u8);
+            request.push_all(username.as_bytes());
+            request.push(password.len() as u8);
+            request.push_all(password.as_bytes());
+            try!(control.write_all(&request[..]));
+
+            let reply = try!(control.read_exact(2));
+            if reply[1] != SUCCEEDED {
+                return Err(proxy_error("The proxy rejected the credentials", None));
+            }
+        }
+
+        let mut request = vec!(VERSION, UDP_ASSOCIATE, 0);
+        encode_addr(&mut request, try!(socket.socket_name()));
+        try!(control.write_all(&request[..]));
+
+        let reply = try!(control.read_exact(4));
+        if reply[0] != VERSION || reply[1] != SUCCEEDED {
+            return Err(proxy_error("The proxy refused to relay datagrams",
+                                   Some(format!("reply code {}", reply[1]))));
+        }
+        let len = match reply[3] {
+            IPV4 => 4 + 2,
+            IPV6 => 16 + 2,
+            ty => return Err(proxy_error("Unsupported relay address",
+                                         Some(format!("address type {}", ty)))),
+        };
+        let mut addr = vec!(reply[3]);
+        addr.push_all(&try!(control.read_exact(len))[..]);
+        let mut relay = match decode_addr(&addr[..]) {
+            Some((relay, _)) => relay,
+            None => return Err(proxy_error("Unsupported relay address", None)),
+        };
+
+        // An unspecified address stands for the proxy's own
+        if relay.ip == Ipv4Addr(0, 0, 0, 0) || relay.ip == Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0) {
+            relay.ip = proxy.ip;
+        }
+
+        Ok(Socks5Transport {
+            socket: socket,
+            relay: relay,
+            _control: control,
+            buf: repeat(0).take(RELAY_BUF_SIZE).collect(),
+        })
+    }
+}
+
+impl DatagramTransport for Socks5Transport {
+    fn send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<()> {
+        let mut datagram = vec!(0, 0, 0);
+        encode_addr(&mut datagram, dst);
+        datagram.push_all(buf);
+        self.socket.send_to(&datagram[..], self.relay)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        loop {
+            let (read, src) = try!(self.socket.recv_from(&mut self.buf[..]));
+            let datagram = &self.buf[..read];
+
+            // Only the relay may talk to us, and never in fragments
+            if src != self.relay || read < 3 || datagram[2] != 0 {
+                debug!("dropping datagram from {} not relayed by the proxy", src);
+                continue;
+            }
+
+            if let Some((from, len)) = decode_addr(&datagram[3..]) {
+                let read = buf.clone_from_slice(&datagram[3 + len..]);
+                return Ok((read, from));
+            }
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.socket.set_read_timeout(timeout_ms)
+    }
+
+    fn poll(&mut self, timeout_ms: Option<u64>) -> IoResult<bool> {
+        DatagramTransport::poll(&mut self.socket, timeout_ms)
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+
+    fn try_clone(&self) -> Option<Box<DatagramTransport + Send>> {
+        // The clone keeps the association alive as well
+        Some(Box::new(Socks5Transport {
+            socket: self.socket.clone(),
+            relay: self.relay,
+            _control: self._control.clone(),
+            buf: repeat(0).take(RELAY_BUF_SIZE).collect(),
+        }))
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<Fd> {
+        Some(AsRawFd::as_raw_fd(&self.socket))
+    }
+}
+
+fn proxy_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: ConnectionFailed,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+/// Append an address in SOCKS5 format.
+fn encode_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip {
+        Ipv4Addr(a, b, c, d) => {
+            out.push(IPV4);
+            out.push_all(&[a, b, c, d]);
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            out.push(IPV6);
+            for &x in [a, b, c, d, e, f, g, h].iter() {
+                out.push((x >> 8) as u8);
+                out.push(x as u8);
+            }
+        },
+    }
+    out.push((addr.port >> 8) as u8);
+    out.push(addr.port as u8);
+}
+
+/// Decode an address in SOCKS5 format, returning it along with its encoded
+/// length.
+fn decode_addr(buf: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (ip, len): (IpAddr, usize) = match buf.first() {
+        Some(&IPV4) if buf.len() >= 1 + 4 + 2 => {
+            (Ipv4Addr(buf[1], buf[2], buf[3], buf[4]), 1 + 4)
+        },
+        Some(&IPV6) if buf.len() >= 1 + 16 + 2 => {
+            let x = |i: usize| (buf[1 + 2 * i] as u16) << 8 | buf[2 + 2 * i] as u16;
+            (Ipv6Addr(x(0), x(1), x(2), x(3), x(4), x(5), x(6), x(7)), 1 + 16)
+        },
+        _ => return None,
+    };
+    let port = (buf[len] as u16) << 8 | buf[len + 1] as u16;
+    Some((SocketAddr { ip: ip, port: port }, len + 2))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Socks5Transport, encode_addr, decode_addr};
+    use std::old_io::{Listener, Acceptor};
+    use std::old_io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+    use std::old_io::net::tcp::TcpListener;
+    use std::old_io::net::udp::UdpSocket;
+    use std::thread;
+    use transport::DatagramTransport;
+
+    fn loopback() -> SocketAddr {
+        SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }
+    }
+
+    #[test]
+    fn test_address_encoding() {
+        let addrs = [
+            SocketAddr { ip: Ipv4Addr(192, 0, 2, 1), port: 6881 },
+            SocketAddr { ip: Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), port: 443 },
+        ];
+        for &addr in addrs.iter() {
+            let mut buf = Vec::new();
+            encode_addr(&mut buf, addr);
+            assert_eq!(decode_addr(&buf[..]), Some((addr, buf.len())));
+            assert_eq!(decode_addr(&buf[..buf.len() - 1]), None);
+        }
+        assert_eq!(decode_addr(&[3, 4, b'h', b'o', b's', b't', 0, 80]), None);
+    }
+
+    #[test]
+    fn test_relay_through_proxy() {
+        let mut listener = TcpListener::bind(loopback()).unwrap();
+        let proxy_addr = listener.socket_name().unwrap();
+        let mut acceptor = listener.listen().unwrap();
+
+        // A proxy relaying a single datagram each way
+        thread::spawn(move || {
+            let mut control = acceptor.accept().unwrap();
+            assert_eq!(control.read_exact(3).unwrap(), vec!(5, 1, 0));
+            control.write_all(&[5, 0]).unwrap();
+            assert_eq!(&control.read_exact(4).unwrap()[..], &[5, 3, 0, 1][..]);
+            control.read_exact(4 + 2).unwrap();
+
+            let mut relay = UdpSocket::bind(loopback()).unwrap();
+            let port = relay.socket_name().unwrap().port;
+            control.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, (port >> 8) as u8, port as u8]).unwrap();
+
+            let mut buf = [0; 1500];
+            let (read, client) = relay.recv_from(&mut buf).unwrap();
+            let (dst, len) = decode_addr(&buf[3..read]).unwrap();
+            relay.send_to(&buf[3 + len..read], dst).unwrap();
+
+            let (read, src) = relay.recv_from(&mut buf).unwrap();
+            let mut reply = vec!(0, 0, 0);
+            encode_addr(&mut reply, src);
+            reply.push_all(&buf[..read]);
+            relay.send_to(&reply[..], client).unwrap();
+        });
+
+        let mut peer = UdpSocket::bind(loopback()).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let mut transport = Socks5Transport::connect(proxy_addr, loopback()).unwrap();
+
+        // Clones relay through the same association
+        let mut clone = transport.try_clone().unwrap();
+        clone.send_to(b"ping", peer_addr).unwrap();
+
+        let mut buf = [0; 1500];
+        let (read, relay) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], &b"ping"[..]);
+        peer.send_to(b"pong", relay).unwrap();
+
+        let (read, src) = transport.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], &b"pong"[..]);
+        assert_eq!(src, peer_addr);
+    }
+}
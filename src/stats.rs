@@ -0,0 +1,302 @@
+use std::cmp::{min, max};
+use std::fmt;
+use std::num::Int;
+
+/// Number of buckets in the reorder distance histogram.
+pub const REORDER_BUCKETS: usize = 8;
+
+/// Number of buckets in a latency histogram: four for each power of two up
+/// to 2^32 microseconds.
+const LATENCY_BUCKETS: usize = 124;
+
+/// Distribution of latency samples, in microseconds.
+///
+/// Like an HDR histogram, each power of two is split in four buckets, so
+/// percentiles are accurate to within 25% whatever the magnitude of the
+/// samples.
+#[derive(Copy)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKETS],
+    count: u64,
+    max: u32,
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram.
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: [0; LATENCY_BUCKETS],
+            count: 0,
+            max: 0,
+        }
+    }
+
+    /// Account for a sample, in microseconds.
+    pub fn record(&mut self, microseconds: u32) {
+        self.counts[latency_bucket(microseconds)] += 1;
+        self.count += 1;
+        if microseconds > self.max {
+            self.max = microseconds;
+        }
+    }
+
+    /// Return the number of samples recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Return the largest sample recorded, in microseconds.
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    /// Return the value below which `percentile` percent of the samples fall,
+    /// in microseconds, or `None` if no sample was recorded.
+    ///
+    /// The value is the upper bound of the bucket holding the matching sample,
+    /// so it never underestimates latency.
+    pub fn percentile(&self, percentile: f64) -> Option<u32> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (percentile / 100.0 * self.count as f64).ceil() as u64;
+        let rank = min(max(rank, 1), self.count);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(min(latency_bucket_end(bucket), self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+impl Clone for LatencyHistogram {
+    fn clone(&self) -> LatencyHistogram {
+        *self
+    }
+}
+
+impl PartialEq for LatencyHistogram {
+    fn eq(&self, other: &LatencyHistogram) -> bool {
+        &self.counts[..] == &other.counts[..] && self.count == other.count && self.max == other.max
+    }
+}
+
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LatencyHistogram {{ count: {}, p50: {:?}, p99: {:?}, max: {} }}",
+               self.count, self.percentile(50.0), self.percentile(99.0), self.max)
+    }
+}
+
+/// Return the index of the bucket holding `value`.
+fn latency_bucket(value: u32) -> usize {
+    if value < 4 {
+        return value as usize;
+    }
+
+    // Index of the most significant bit set, then the next two bits
+    let msb = 31 - value.leading_zeros() as usize;
+    (msb - 1) * 4 + ((value >> (msb - 2)) & 3) as usize
+}
+
+/// Return the largest value held by a bucket.
+fn latency_bucket_end(bucket: usize) -> u32 {
+    if bucket < 4 {
+        return bucket as u32;
+    }
+
+    let msb = bucket / 4 + 1;
+    let start = (4 + (bucket % 4) as u64) << (msb - 2);
+    (start + (1 << (msb - 2)) - 1) as u32
+}
+
+/// Number of received packets dropped, by reason.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct DropCounters {
+    /// Packets that failed to parse, or whose payload failed to decode.
+    pub malformed: u64,
+    /// Packets for another connection: with a foreign connection id, or from
+    /// an unexpected address.
+    pub unknown_connection: u64,
+    /// Packets unexpected in the connection's current state.
+    pub wrong_state: u64,
+    /// Data packets received before, and packets acknowledging data never
+    /// sent.
+    pub out_of_window: u64,
+    /// Data packets received out of order that didn't fit in the receive
+    /// buffer, or were discarded to make room for newer ones.
+    pub buffer_full: u64,
+    /// Packets left unanswered because too many resets were sent recently.
+    /// Most of them are also counted under the reason they were rejected for,
+    /// so they aren't part of `total`.
+    pub rate_limited: u64,
+}
+
+impl DropCounters {
+    /// Create a new set of zeroed counters.
+    pub fn new() -> DropCounters {
+        DropCounters {
+            malformed: 0,
+            unknown_connection: 0,
+            wrong_state: 0,
+            out_of_window: 0,
+            buffer_full: 0,
+            rate_limited: 0,
+        }
+    }
+
+    /// Return the number of packets dropped for any reason.
+    pub fn total(&self) -> u64 {
+        self.malformed + self.unknown_connection + self.wrong_state + self.out_of_window +
+            self.buffer_full
+    }
+}
+
+/// Counters describing the behaviour of a connection.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct SocketStats {
+    /// Number of data packets that arrived after a packet with a higher
+    /// sequence number.
+    pub packets_reordered: u64,
+    /// Largest sequence number distance by which a packet arrived late.
+    pub max_reorder_distance: u16,
+    /// Histogram of reorder distances, in powers of two: bucket `i` counts
+    /// packets that arrived between `2^i` and `2^(i+1) - 1` sequence numbers
+    /// late, the last bucket counting everything beyond.
+    pub reorder_histogram: [u64; REORDER_BUCKETS],
+    /// Number of resets sent in reply to unexpected packets.
+    pub resets_sent: u64,
+    /// Number of resets withheld because too many were sent recently.
+    pub resets_suppressed: u64,
+    /// Bytes currently held in the connection's send and receive buffers.
+    pub memory_usage: usize,
+    /// Bytes of data queued for sending since the connection opened.
+    pub bytes_queued: u64,
+    /// Number of times the congestion window was halved after a packet loss.
+    pub cwnd_halvings: u64,
+    /// Number of times data in flight went unacknowledged until the
+    /// congestion timeout, collapsing the congestion window. Applications can
+    /// watch this and `cwnd_halvings` to adapt to severe congestion, e.g. by
+    /// lowering the quality of a stream.
+    pub congestion_timeouts: u64,
+    /// Bytes of data sent while compression was in use, before compression.
+    pub bytes_before_compression: u64,
+    /// Bytes those took once compressed, so that the ratio of this to
+    /// `bytes_before_compression` is the compression ratio achieved.
+    pub bytes_after_compression: u64,
+    /// Latest estimate of the path's bandwidth-delay product, in bytes, from
+    /// the data received over a round trip.
+    pub bdp_estimate: u32,
+    /// Distribution of the round-trip times measured from acknowledgements.
+    pub rtt_histogram: LatencyHistogram,
+    /// Distribution of the queuing delays measured from acknowledgements,
+    /// which the congestion controller keeps near its target.
+    pub queuing_delay_histogram: LatencyHistogram,
+    /// Number of parity packets sent.
+    pub parity_packets_sent: u64,
+    /// Number of lost data packets rebuilt from parity packets.
+    pub packets_recovered: u64,
+    /// Received packets dropped, by reason. When a transfer stalls, these
+    /// tell whether the peer's packets arrive at all, and why they are
+    /// ignored.
+    pub drops: DropCounters,
+}
+
+impl SocketStats {
+    /// Create a new set of zeroed counters.
+    pub fn new() -> SocketStats {
+        SocketStats {
+            packets_reordered: 0,
+            max_reorder_distance: 0,
+            reorder_histogram: [0; REORDER_BUCKETS],
+            resets_sent: 0,
+            resets_suppressed: 0,
+            memory_usage: 0,
+            bytes_queued: 0,
+            cwnd_halvings: 0,
+            congestion_timeouts: 0,
+            bytes_before_compression: 0,
+            bytes_after_compression: 0,
+            bdp_estimate: 0,
+            rtt_histogram: LatencyHistogram::new(),
+            queuing_delay_histogram: LatencyHistogram::new(),
+            parity_packets_sent: 0,
+            packets_recovered: 0,
+            drops: DropCounters::new(),
+        }
+    }
+
+    /// Account for a packet arriving `distance` sequence numbers late.
+    pub fn record_reorder(&mut self, distance: u16) {
+        if distance == 0 {
+            return;
+        }
+
+        self.packets_reordered += 1;
+        if distance > self.max_reorder_distance {
+            self.max_reorder_distance = distance;
+        }
+
+        // Index of the most significant bit set
+        let bucket = 15 - distance.leading_zeros() as usize;
+        self.reorder_histogram[min(bucket, REORDER_BUCKETS - 1)] += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SocketStats, LatencyHistogram, REORDER_BUCKETS, latency_bucket, latency_bucket_end};
+
+    #[test]
+    fn test_record_reorder() {
+        let mut stats = SocketStats::new();
+        stats.record_reorder(0);
+        assert_eq!(stats, SocketStats::new());
+
+        for &distance in [1, 2, 3, 4, 200, 1000].iter() {
+            stats.record_reorder(distance);
+        }
+
+        assert_eq!(stats.packets_reordered, 6);
+        assert_eq!(stats.max_reorder_distance, 1000);
+        assert_eq!(stats.reorder_histogram[0], 1);
+        assert_eq!(stats.reorder_histogram[1], 2);
+        assert_eq!(stats.reorder_histogram[2], 1);
+        assert_eq!(stats.reorder_histogram[REORDER_BUCKETS - 1], 2);
+    }
+
+    #[test]
+    fn test_latency_buckets() {
+        for &value in [0, 3, 4, 7, 8, 9, 1000, 123_456, ::std::u32::MAX].iter() {
+            let bucket = latency_bucket(value);
+            assert!(latency_bucket_end(bucket) >= value);
+            assert!(bucket == 0 || latency_bucket_end(bucket - 1) < value);
+        }
+        assert_eq!(latency_bucket(::std::u32::MAX), 123);
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.percentile(50.0), None);
+
+        // 98 samples around 10 ms and two outliers
+        for _ in 0..98 {
+            histogram.record(10_000);
+        }
+        histogram.record(200_000);
+        histogram.record(1_000_000);
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), 1_000_000);
+        let median = histogram.percentile(50.0).unwrap();
+        assert!(median >= 10_000 && median < 12_500);
+        let p99 = histogram.percentile(99.0).unwrap();
+        assert!(p99 >= 200_000 && p99 < 250_000);
+        assert_eq!(histogram.percentile(100.0), Some(1_000_000));
+    }
+}
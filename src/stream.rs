@@ -1,22 +1,108 @@
 use std::old_io::IoResult;
 use std::old_io::net::ip::{SocketAddr, Ipv4Addr};
-use socket::UtpSocket;
+use socket::{UtpSocket, CloseReason, BufferLevel, Shutdown};
+use error::UtpError;
+use time::SteadyTime;
+
+/// Default capacity of a stream's write buffer.
+const WRITE_BUF_SIZE: usize = 64 * 1024;
 
 /// Stream interface for UtpSocket.
+///
+/// Streams implement `Reader` and `Writer`, so they can stand in for a TCP
+/// stream. Reads return whatever data is available, however small the
+/// buffer, and end with an `EndOfFile` error once the peer closed the
+/// connection. Small writes are buffered and handed to the socket in larger
+/// batches, when the buffer fills up, on `flush`, on `close`, before the
+/// stream waits for incoming data, and when the stream is dropped.
+///
+/// # Examples
+///
+/// ```
+/// use utp::UtpStream;
+/// use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+/// use std::thread;
+///
+/// let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+/// let mut server = UtpStream::bind(addr).unwrap();
+/// let addr = server.local_addr().unwrap();
+///
+/// thread::spawn(move || {
+///     let mut client = UtpStream::connect(addr).unwrap();
+///     client.write_all(&[1, 2, 3]).unwrap();
+///     client.flush().unwrap();
+///     client.close().unwrap();
+/// });
+///
+/// let received = server.read_to_end().unwrap();
+/// assert_eq!(received, vec![1, 2, 3]);
+/// server.close().unwrap();
+/// ```
 pub struct UtpStream {
     socket: UtpSocket,
+    write_buf: Vec<u8>,
+    write_buf_size: usize,
 }
 
 impl UtpStream {
+    fn new(socket: UtpSocket) -> UtpStream {
+        UtpStream {
+            socket: socket,
+            write_buf: Vec::new(),
+            write_buf_size: WRITE_BUF_SIZE,
+        }
+    }
+
     /// Create a uTP stream listening on the given address.
     #[unstable]
     pub fn bind(addr: SocketAddr) -> IoResult<UtpStream> {
         match UtpSocket::bind(addr) {
-            Ok(s)  => Ok(UtpStream { socket: s }),
+            Ok(s)  => Ok(UtpStream::new(s)),
             Err(e) => Err(e),
         }
     }
 
+    /// Return the local address the stream is bound to.
+    #[unstable]
+    pub fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Return the arrival time of the newest packet that contributed data to
+    /// the latest read, if any.
+    #[unstable]
+    pub fn recv_timestamp(&self) -> Option<SteadyTime> {
+        self.socket.recv_timestamp()
+    }
+
+    /// Return why the connection ended, or `None` while it is still open.
+    #[unstable]
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.socket.close_reason()
+    }
+
+    /// Shut down one half of the connection (see `UtpSocket::shutdown`).
+    #[unstable]
+    pub fn shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+        self.socket.shutdown(how)
+    }
+
+    /// Return the last protocol error raised, if any (see
+    /// `UtpSocket::last_error`).
+    #[unstable]
+    pub fn last_error(&self) -> Option<UtpError> {
+        self.socket.last_error()
+    }
+
+    /// Call `callback` when unread data reaches `high` bytes and when it
+    /// drains back to `low` bytes (see `UtpSocket::set_water_marks`).
+    #[unstable]
+    pub fn set_water_marks<F>(&mut self, low: usize, high: usize, callback: F) -> IoResult<()>
+        where F: FnMut(BufferLevel) + Send + 'static
+    {
+        self.socket.set_water_marks(low, high, callback)
+    }
+
     /// Open a uTP connection to a remote host by hostname or IP address.
     #[unstable]
     pub fn connect(dst: SocketAddr) -> IoResult<UtpStream> {
@@ -28,7 +114,7 @@ impl UtpStream {
         };
 
         match socket.connect(dst) {
-            Ok(socket) => Ok(UtpStream { socket: socket }),
+            Ok(socket) => Ok(UtpStream::new(socket)),
             Err(e) => Err(e),
         }
     }
@@ -39,21 +125,83 @@ impl UtpStream {
     /// flight.
     #[unstable]
     pub fn close(&mut self) -> IoResult<()> {
+        try!(self.send_buffered());
         self.socket.close()
     }
+
+    /// Set how many bytes of small writes are held back before being handed
+    /// to the socket. A size of zero disables write buffering.
+    #[unstable]
+    pub fn set_write_buffer_size(&mut self, size: usize) -> IoResult<()> {
+        if self.write_buf.len() > size {
+            try!(self.send_buffered());
+        }
+        self.write_buf_size = size;
+        Ok(())
+    }
+
+    /// Hand buffered writes to the socket.
+    ///
+    /// They are kept if the socket failed before queuing them, so that a
+    /// later call can retry.
+    fn send_buffered(&mut self) -> IoResult<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let queued = self.socket.stats().bytes_queued;
+        let result = self.socket.send(&self.write_buf[..]);
+        if result.is_ok() || self.socket.stats().bytes_queued != queued {
+            self.write_buf.clear();
+        }
+        result.map(|_| ())
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        // Errors can't be reported from here; use `flush` or `close` to see
+        // them
+        let _ = self.send_buffered();
+        let _ = self.socket.flush();
+    }
 }
 
 impl Reader for UtpStream {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        match self.socket.recv_from(buf) {
-            Ok((read, _src)) => Ok(read),
-            Err(e) => Err(e),
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // The peer may be waiting for what we wrote before answering
+        try!(self.send_buffered());
+
+        // Timeouts without data would look like a stream making no progress
+        loop {
+            match self.socket.recv(buf) {
+                Ok(0) => continue,
+                Ok(read) => return Ok(read),
+                Err(e) => return Err(e),
+            }
         }
     }
 }
 
 impl Writer for UtpStream {
     fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
-        self.socket.send_to(buf)
+        if self.write_buf.len() + buf.len() > self.write_buf_size {
+            try!(self.send_buffered());
+        }
+
+        if buf.len() >= self.write_buf_size {
+            self.socket.send(buf).map(|_| ())
+        } else {
+            self.write_buf.push_all(buf);
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        try!(self.send_buffered());
+        self.socket.flush()
     }
 }
@@ -0,0 +1,102 @@
+use std::old_io::{IoResult, IoError, IoUnavailable};
+use std::old_io::net::ip::SocketAddr;
+use std::old_io::net::udp::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::{AsRawFd, Fd};
+use util::poll_readable;
+
+/// Unreliable datagram carrier underneath a uTP socket.
+///
+/// uTP normally runs over UDP, but any transport able to send and receive
+/// datagrams addressed by `SocketAddr` will do, e.g. a tunnel, a proxy or an
+/// in-memory network simulator. Datagrams may be lost, duplicated or
+/// reordered; uTP takes care of that.
+///
+/// Use `UtpSocketBuilder::bind_transport` to create a socket over a custom
+/// transport.
+pub trait DatagramTransport {
+    /// Send a datagram to the given address.
+    fn send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<()>;
+
+    /// Receive a datagram, returning its length and sender.
+    ///
+    /// Must fail with `TimedOut` once the timeout set by `set_read_timeout`
+    /// elapses without any datagram arriving.
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)>;
+
+    /// Set how long `recv_from` waits for a datagram, in milliseconds, or
+    /// wait forever if `None`. A zero timeout polls for datagrams already
+    /// waiting.
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>);
+
+    /// Wait up to `timeout_ms` milliseconds, or forever if `None`, for a
+    /// datagram to arrive, without receiving it. Returns whether one is
+    /// waiting.
+    ///
+    /// A datagram reported here may still be discarded by `recv_from`, e.g.
+    /// if the transport finds it malformed, so `recv_from` may time out
+    /// afterwards all the same.
+    fn poll(&mut self, timeout_ms: Option<u64>) -> IoResult<bool>;
+
+    /// Return the local address of the transport.
+    fn local_addr(&mut self) -> IoResult<SocketAddr>;
+
+    /// Move the transport to a new local address, e.g. after a network
+    /// change, if the transport supports it.
+    fn rebind(&mut self, _addr: SocketAddr) -> IoResult<()> {
+        Err(IoError {
+            kind: IoUnavailable,
+            desc: "The transport can't be moved to another address",
+            detail: None,
+        })
+    }
+
+    /// Return another handle sending through the same transport, usable from
+    /// another thread, if the transport supports it.
+    fn try_clone(&self) -> Option<Box<DatagramTransport + Send>> {
+        None
+    }
+
+    /// Return the file descriptor that becomes readable when a datagram
+    /// arrives, if any, for registering with an event loop such as mio.
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<Fd> {
+        None
+    }
+}
+
+impl DatagramTransport for UdpSocket {
+    fn send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<()> {
+        UdpSocket::send_to(self, buf, dst)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        UdpSocket::set_read_timeout(self, timeout_ms)
+    }
+
+    fn poll(&mut self, timeout_ms: Option<u64>) -> IoResult<bool> {
+        poll_readable(self, timeout_ms)
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket_name()
+    }
+
+    fn rebind(&mut self, addr: SocketAddr) -> IoResult<()> {
+        *self = try!(UdpSocket::bind(addr));
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Option<Box<DatagramTransport + Send>> {
+        Some(Box::new(self.clone()))
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<Fd> {
+        Some(AsRawFd::as_raw_fd(self))
+    }
+}
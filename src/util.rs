@@ -1,11 +1,124 @@
 extern crate time;
 
 use std::num::ToPrimitive;
+use std::old_io::{IoError, IoResult};
+use std::old_io::net::udp::UdpSocket;
 
-/// Return current time in microseconds since the UNIX epoch.
+/// Resolution of the operating system's timers, in milliseconds.
+#[cfg(windows)]
+pub const TIMER_GRANULARITY: u64 = 16;
+/// Resolution of the operating system's timers, in milliseconds.
+#[cfg(not(windows))]
+pub const TIMER_GRANULARITY: u64 = 1;
+
+/// Return current time in microseconds from an arbitrary starting point.
+///
+/// A high-resolution monotonic clock is used, since the system clock has a
+/// resolution of about 15 ms on Windows. Only differences between timestamps
+/// are meaningful.
 pub fn now_microseconds() -> u32 {
-    let t = time::get_time();
-    (t.sec * 1_000_000) as u32 + (t.nsec/1000) as u32
+    (time::precise_time_ns() / 1000) as u32
+}
+
+/// Return `later - earlier` for two 32-bit microsecond timestamps.
+///
+/// Timestamps wrap around about every 71 minutes, so plain subtraction yields
+/// bogus results when one of them wrapped. This is correct as long as the
+/// timestamps are less than about 35 minutes apart, in either direction.
+pub fn timestamp_difference(later: u32, earlier: u32) -> i64 {
+    later.wrapping_sub(earlier) as i32 as i64
+}
+
+/// Check whether a UDP receive error was caused by an ICMP message about a
+/// previously sent datagram rather than by a problem with the socket itself.
+///
+/// Linux reports ICMP port unreachable messages as `ECONNREFUSED` on the next
+/// receive, while Windows reports them as `WSAECONNRESET`, even on
+/// unconnected sockets.
+pub fn is_icmp_error(e: &IoError) -> bool {
+    use std::old_io::{ConnectionReset, ConnectionRefused};
+    e.kind == ConnectionReset || e.kind == ConnectionRefused
+}
+
+/// Check whether an error may go away if the operation is retried, e.g.
+/// when the operating system briefly ran out of buffers.
+pub fn is_transient_error(e: &IoError) -> bool {
+    use std::old_io::{TimedOut, ResourceUnavailable, NoProgress};
+    e.kind == TimedOut || e.kind == ResourceUnavailable || e.kind == NoProgress
+}
+
+/// Restrict a socket to sending and receiving through the named network
+/// interface.
+#[cfg(target_os = "linux")]
+pub fn bind_to_device(socket: &UdpSocket, name: &str) -> IoResult<()> {
+    use std::os::unix::AsRawFd;
+    use libc::{setsockopt, c_int, c_void, socklen_t, SOL_SOCKET};
+    const SO_BINDTODEVICE: c_int = 25;
+
+    let ret = unsafe {
+        setsockopt(socket.as_raw_fd(), SOL_SOCKET, SO_BINDTODEVICE,
+                   name.as_ptr() as *const c_void, name.len() as socklen_t)
+    };
+
+    if ret == 0 { Ok(()) } else { Err(IoError::last_error()) }
+}
+
+/// Restrict a socket to sending and receiving through the named network
+/// interface.
+#[cfg(not(target_os = "linux"))]
+pub fn bind_to_device(_socket: &UdpSocket, _name: &str) -> IoResult<()> {
+    use std::old_io::IoUnavailable;
+    Err(IoError {
+        kind: IoUnavailable,
+        desc: "Binding to a network interface is not supported on this platform",
+        detail: None,
+    })
+}
+
+/// Wait up to `timeout_ms` milliseconds, or forever if `None`, for a socket to
+/// become readable, returning whether it did.
+#[cfg(unix)]
+pub fn poll_readable(socket: &UdpSocket, timeout_ms: Option<u64>) -> IoResult<bool> {
+    use std::cmp::min;
+    use std::i32;
+    use std::os::unix::AsRawFd;
+    use libc::{c_int, c_short};
+    const POLLIN: c_short = 1;
+
+    #[cfg(target_os = "linux")]
+    type NfdsT = ::libc::c_ulong;
+    #[cfg(not(target_os = "linux"))]
+    type NfdsT = ::libc::c_uint;
+
+    #[repr(C)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    extern {
+        fn poll(fds: *mut PollFd, nfds: NfdsT, timeout: c_int) -> c_int;
+    }
+
+    let mut fd = PollFd { fd: socket.as_raw_fd(), events: POLLIN, revents: 0 };
+    let timeout = timeout_ms.map_or(-1, |ms| min(ms, i32::MAX as u64) as c_int);
+    match unsafe { poll(&mut fd, 1, timeout) } {
+        n if n < 0 => Err(IoError::last_error()),
+        n => Ok(n > 0),
+    }
+}
+
+/// Wait up to `timeout_ms` milliseconds, or forever if `None`, for a socket to
+/// become readable, returning whether it did.
+#[cfg(not(unix))]
+pub fn poll_readable(_socket: &UdpSocket, _timeout_ms: Option<u64>) -> IoResult<bool> {
+    use std::old_io::IoUnavailable;
+    Err(IoError {
+        kind: IoUnavailable,
+        desc: "Polling sockets is not supported on this platform",
+        detail: None,
+    })
 }
 
 /// Calculate the exponential weighted moving average for a vector of numbers, with a smoothing
@@ -19,6 +132,29 @@ pub fn ewma<T: ToPrimitive>(samples: Vec<T>, alpha: f64) -> f64 {
 }
 
 mod test {
+    #[test]
+    fn test_now_microseconds_is_monotonic() {
+        use super::now_microseconds;
+
+        let before = now_microseconds();
+        let after = now_microseconds();
+        assert!(after.wrapping_sub(before) < 1_000_000);
+    }
+
+    #[test]
+    fn test_timestamp_difference() {
+        use super::timestamp_difference;
+        use std::u32;
+
+        assert_eq!(timestamp_difference(1500, 1000), 500);
+        assert_eq!(timestamp_difference(1000, 1500), -500);
+
+        // Around the wrap point
+        assert_eq!(timestamp_difference(100, u32::MAX - 99), 200);
+        assert_eq!(timestamp_difference(u32::MAX - 99, 100), -200);
+        assert_eq!(timestamp_difference(0, u32::MAX), 1);
+    }
+
     #[test]
     fn test_ewma_empty_vector() {
         use super::ewma;
@@ -49,4 +185,25 @@ mod test {
         46439.0/6561.0, 158488.0/19683.0];
         assert_eq!(ewma(input, alpha), expected[expected.len() - 1]);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_poll_readable() {
+        use super::poll_readable;
+        use std::old_io::net::ip::{SocketAddr, Ipv4Addr};
+        use std::old_io::net::udp::UdpSocket;
+
+        let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+        let mut socket = UdpSocket::bind(addr).unwrap();
+        let socket_addr = socket.socket_name().unwrap();
+        assert_eq!(poll_readable(&socket, Some(0)), Ok(false));
+
+        // A waiting datagram is reported without being received
+        socket.send_to(&[1, 2, 3], socket_addr).unwrap();
+        assert_eq!(poll_readable(&socket, Some(1000)), Ok(true));
+        assert_eq!(poll_readable(&socket, Some(0)), Ok(true));
+        let mut buf = [0; 3];
+        assert_eq!(socket.recv_from(&mut buf), Ok((3, socket_addr)));
+        assert_eq!(poll_readable(&socket, Some(0)), Ok(false));
+    }
 }
@@ -1,6 +1,7 @@
 extern crate utp;
 
-use std::old_io::test::next_test_ip4;
+use std::cmp::min;
+use std::old_io::net::ip::{SocketAddr, Ipv4Addr};
 use std::thread;
 use utp::UtpStream;
 
@@ -8,10 +9,18 @@ macro_rules! iotry {
     ($e:expr) => (match $e { Ok(e) => e, Err(e) => panic!("{}", e) })
 }
 
+/// Bind a stream to a loopback address with a port assigned by the operating
+/// system, returning it along with its address.
+fn bind_ephemeral() -> (UtpStream, SocketAddr) {
+    let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let mut stream = iotry!(UtpStream::bind(addr));
+    let addr = iotry!(stream.local_addr());
+    (stream, addr)
+}
+
 #[test]
 fn test_stream_open_and_close() {
-    let server_addr = next_test_ip4();
-    let mut server = iotry!(UtpStream::bind(server_addr));
+    let (mut server, server_addr) = bind_ephemeral();
 
     thread::spawn(move || {
         let mut client = iotry!(UtpStream::connect(server_addr));
@@ -31,8 +40,7 @@ fn test_stream_small_data() {
     assert_eq!(LEN, data.len());
 
     let d = data.clone();
-    let server_addr = next_test_ip4();
-    let mut server = UtpStream::bind(server_addr);
+    let (mut server, server_addr) = bind_ephemeral();
 
     thread::spawn(move || {
         let mut client = iotry!(UtpStream::connect(server_addr));
@@ -54,8 +62,7 @@ fn test_stream_large_data() {
     assert_eq!(LEN, data.len());
 
     let d = data.clone();
-    let server_addr = next_test_ip4();
-    let mut server = UtpStream::bind(server_addr);
+    let (mut server, server_addr) = bind_ephemeral();
 
     thread::spawn(move || {
         let mut client = iotry!(UtpStream::connect(server_addr));
@@ -78,8 +85,7 @@ fn test_stream_successive_reads() {
     assert_eq!(LEN, data.len());
 
     let d = data.clone();
-    let server_addr = next_test_ip4();
-    let mut server = UtpStream::bind(server_addr);
+    let (mut server, server_addr) = bind_ephemeral();
 
     thread::spawn(move || {
         let mut client = iotry!(UtpStream::connect(server_addr));
@@ -95,3 +101,62 @@ fn test_stream_successive_reads() {
         e => panic!("should have failed with Closed, got {:?}", e),
     };
 }
+
+#[test]
+fn test_stream_buffered_writes_and_partial_reads() {
+    const LEN: usize = 10000;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+
+    let d = data.clone();
+    let (mut server, server_addr) = bind_ephemeral();
+
+    thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        // Byte by byte, coalesced by the write buffer
+        for byte in d.iter() {
+            iotry!(client.write_u8(*byte));
+        }
+        iotry!(client.flush());
+
+        // Unbuffered, each write goes straight to the socket
+        iotry!(client.set_write_buffer_size(0));
+        iotry!(client.write_all(&d[..10]));
+        iotry!(client.close());
+    });
+
+    // Reads return as soon as some data is available
+    let mut read = Vec::new();
+    let mut buf = [0u8; 7];
+    while read.len() < LEN {
+        let want = min(buf.len(), LEN - read.len());
+        let len = iotry!(server.read(&mut buf[..want]));
+        assert!(len > 0 && len <= want);
+        read.push_all(&buf[..len]);
+    }
+    assert_eq!(read, data);
+    assert_eq!(iotry!(server.read_to_end()), data[..10].to_vec());
+}
+
+#[test]
+fn test_stream_flushes_on_drop() {
+    const LEN: usize = 100;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+
+    let d = data.clone();
+    let (mut server, server_addr) = bind_ephemeral();
+
+    thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        // Small enough to stay in the write buffer until the stream is dropped
+        iotry!(client.write_all(&d[..]));
+        drop(client);
+    });
+
+    let mut read = Vec::new();
+    let mut buf = [0u8; LEN];
+    while read.len() < LEN {
+        let len = iotry!(server.read(&mut buf[..LEN - read.len()]));
+        read.push_all(&buf[..len]);
+    }
+    assert_eq!(read, data);
+}